@@ -0,0 +1,186 @@
+//! The attribute macros of [debug-log](https://docs.rs/debug-log). Use them through
+//! the re-exports of `debug-log`.
+use proc_macro::TokenStream;
+use proc_macro2::{Span, TokenTree};
+use quote::quote;
+use syn::{
+    parse_macro_input, parse_quote, spanned::Spanned, visit_mut::VisitMut, Expr, ExprBreak, FnArg,
+    Ident, ItemFn, Lifetime, Pat, ReturnType, Stmt, Type,
+};
+
+#[derive(Default)]
+struct Options {
+    args: bool,
+    ret: bool,
+    skip: Vec<Ident>,
+}
+
+impl Options {
+    fn parse(&mut self, meta: syn::meta::ParseNestedMeta) -> syn::Result<()> {
+        if meta.path.is_ident("args") {
+            self.args = true;
+        } else if meta.path.is_ident("ret") {
+            self.ret = true;
+        } else if meta.path.is_ident("skip") {
+            meta.parse_nested_meta(|meta| {
+                self.skip.push(meta.path.require_ident()?.clone());
+                Ok(())
+            })?;
+        } else {
+            return Err(meta.error("expected `args`, `ret` or `skip(..)`"));
+        }
+        Ok(())
+    }
+}
+
+/// Group everything logged inside the function, like calling `group!()` at the start of
+/// it. The group is labeled with the function and `file:line`.
+///
+/// - `#[group_fn(args)]` logs the arguments like `debug_dbg!`
+/// - `#[group_fn(skip(a, b))]` doesn't log the arguments `a` and `b`, e.g. when they
+///   don't implement `Debug`
+/// - `#[group_fn(ret)]` logs the return value. It's not supported on async functions.
+///   `?` only works on `Result` and `Option` there, and a `return` or `?` inside
+///   another macro, like `format!("{}", x?)`, returns without logging
+///
+/// On an `async fn`, the group stays open across its `.await`s, so the output of the
+/// other tasks polled meanwhile on the thread nests inside it. Use
+/// `.debug_group(..)` on the future to keep the groups of each task apart.
+///
+/// Like the other macros, it adds nothing in release mode.
+#[proc_macro_attribute]
+pub fn group_fn(attr: TokenStream, item: TokenStream) -> TokenStream {
+    let mut options = Options::default();
+    let parser = syn::meta::parser(|meta| options.parse(meta));
+    parse_macro_input!(attr with parser);
+    let mut item = parse_macro_input!(item as ItemFn);
+    match expand(&options, &mut item) {
+        Ok(()) => quote!(#item).into(),
+        Err(err) => err.to_compile_error().into(),
+    }
+}
+
+fn expand(options: &Options, item: &mut ItemFn) -> syn::Result<()> {
+    let mut names = Vec::new();
+    for arg in item.sig.inputs.iter() {
+        if let FnArg::Typed(arg) = arg {
+            if let Pat::Ident(pat) = &*arg.pat {
+                names.push(pat.ident.clone());
+            }
+        }
+    }
+    if let Some(ident) = options.skip.iter().find(|x| !names.contains(x)) {
+        return Err(syn::Error::new(
+            ident.span(),
+            format!("`{}` is not an argument of this function", ident),
+        ));
+    }
+
+    let args: Vec<_> = if options.args {
+        names
+            .into_iter()
+            .filter(|x| !options.skip.contains(x))
+            .collect()
+    } else {
+        Vec::new()
+    };
+    let block = if options.ret {
+        if let Some(asyncness) = item.sig.asyncness {
+            return Err(syn::Error::new(
+                asyncness.span(),
+                "`ret` is not supported on async functions",
+            ));
+        }
+
+        let ty = match &item.sig.output {
+            ReturnType::Default => quote!(()),
+            // `impl Trait` can't be the type of a variable, so let it be inferred
+            ReturnType::Type(_, ty) if contains_impl(ty) => quote!(_),
+            ReturnType::Type(_, ty) => quote!(#ty),
+        };
+        // The body stays inline so that it can return the borrows of the arguments.
+        // Its `return`s become `break`s out of a labeled block, so the value is logged
+        let mut rewrite = Rewrite {
+            label: Lifetime::new("'__group_fn_ret", Span::mixed_site()),
+            used: false,
+        };
+        rewrite.visit_block_mut(&mut item.block);
+        let body = &item.block;
+        let body = if rewrite.used {
+            let label = &rewrite.label;
+            quote!(#label: #body)
+        } else {
+            quote!(#body)
+        };
+        quote!(::debug_log::__group_fn!([#(#args),*], ret #ty, #body))
+    } else {
+        let body = &item.block;
+        quote!(::debug_log::__group_fn!([#(#args),*], #body))
+    };
+    // Not parsed again, as syn reads `break 'label ::path` as a labeled block
+    item.block.stmts = vec![Stmt::Expr(Expr::Verbatim(block), None)];
+    Ok(())
+}
+
+/// Turns the `return`s and `?`s of the function into `break`s to `label`
+struct Rewrite {
+    label: Lifetime,
+    used: bool,
+}
+
+impl Rewrite {
+    fn break_with(&mut self, value: Expr) -> Expr {
+        self.used = true;
+        Expr::Break(ExprBreak {
+            attrs: Vec::new(),
+            break_token: Default::default(),
+            label: Some(self.label.clone()),
+            expr: Some(Box::new(value)),
+        })
+    }
+}
+
+impl VisitMut for Rewrite {
+    fn visit_expr_mut(&mut self, expr: &mut Expr) {
+        match expr {
+            // They have their own `return`s
+            Expr::Closure(_) | Expr::Async(_) => {}
+            Expr::Return(ret) => {
+                if let Some(value) = ret.expr.as_mut() {
+                    self.visit_expr_mut(value);
+                }
+                let value = ret.expr.take().unwrap_or_else(|| parse_quote!(()));
+                *expr = self.break_with(*value);
+            }
+            Expr::Try(t) => {
+                self.visit_expr_mut(&mut t.expr);
+                let value = &t.expr;
+                let residual = self.break_with(parse_quote!(
+                    ::debug_log::__private::FromResidual::from_residual(residual)
+                ));
+                *expr = Expr::Verbatim(quote!(
+                    match ::debug_log::__private::Branch::branch(#value) {
+                        ::core::result::Result::Ok(x) => x,
+                        ::core::result::Result::Err(residual) => #residual,
+                    }
+                ));
+            }
+            _ => syn::visit_mut::visit_expr_mut(self, expr),
+        }
+    }
+
+    // Nested functions have their own `return`s
+    fn visit_item_mut(&mut self, _: &mut syn::Item) {}
+}
+
+fn contains_impl(ty: &Type) -> bool {
+    fn visit(tokens: proc_macro2::TokenStream) -> bool {
+        tokens.into_iter().any(|x| match x {
+            TokenTree::Ident(ident) => ident == "impl",
+            TokenTree::Group(group) => visit(group.stream()),
+            _ => false,
+        })
+    }
+
+    visit(quote!(#ty))
+}
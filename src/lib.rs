@@ -3,9 +3,27 @@
 //!
 //! - 🦀 Enabled only in debug mode when DEBUG environment variable is set
 //! - 🔊 Only perform log in files whose paths match `DEBUG="filename"`. Match all by
-//!   using `DEBUG=""`, or `DEBUG="*"`
-//! - 📦 Group output with `debug_group`
-//! - 📤 WASM support. It will use the console API.
+//!   using `DEBUG="*"` or `DEBUG=1`, and turn it off by `DEBUG=""` or `DEBUG=0`.
+//!   Narrow it down to lines with `DEBUG="filename:12"`, `DEBUG="filename:200-400"`
+//!   or `DEBUG="filename:200-"`
+//! - 🏷️ Log everything inside the groups whose labels match `DEBUG="group:label"`.
+//!   Multiple patterns are separated by commas, e.g. `DEBUG="group:label,filename"`
+//! - 🧭 Match the module path with `DEBUG="mod:my_crate::state"`
+//! - 📦 Group output with `group!` or `group_scope`, or across scopes with
+//!   `group_begin!` and `group_end!`
+//! - ⏳ Keep the groups of each async task apart with `fut.debug_group("label")`
+//! - 🪆 Group a whole function with `#[group_fn]`, optionally logging its arguments
+//!   with `#[group_fn(args)]` and its return value with `#[group_fn(ret)]`
+//! - ↔️ Change the indentation by `DEBUG_INDENT=2` or `set_indent("│   ")`
+//! - 🧪 Write to stdout with `DEBUG_TARGET=stdout`, so `cargo test` only shows the
+//!   output of the failed tests
+//! - 🔁 Collapse consecutive duplicate entries with `DEBUG_DEDUP=1`
+//! - 📝 Append the output to a file with `DEBUG_FILE=/tmp/debug.log`
+//! - 💥 Keep the last entries and dump them on panic with `DEBUG_ON_PANIC=500`
+//! - 🤏 Print small values on one line with `debug_dbg_compact!`
+//! - 🔢 Hexdump bytes with `debug_hex!`, and render tables with `debug_table!`
+//! - 📤 WASM support. It will use the console API with the `wasm` feature. On WASI,
+//!   it writes to stderr
 //!
 //! The output log is super easy to read on VS Code with sticky scroll enabled.
 //!
@@ -66,26 +84,829 @@
 //! }
 //! ```
 
-#[cfg(all(debug_assertions))]
+/// The time prefix of each log entry
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TimeMode {
+    /// No prefix
+    #[default]
+    Off,
+    /// Wall-clock time in UTC, `HH:MM:SS.mmm`
+    Abs,
+    /// Milliseconds since the first log call
+    Elapsed,
+}
+
+/// Whether to color the output with ANSI escape codes
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ColorMode {
+    /// Color when writing to a terminal and `NO_COLOR` is not set
+    #[default]
+    Auto,
+    Always,
+    Never,
+}
+
+/// How groups are displayed in the browser console
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum WasmGroupMode {
+    /// `console.group`
+    #[default]
+    Expanded,
+    /// `console.groupCollapsed`
+    Collapsed,
+}
+
+/// The format of the output
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Format {
+    /// Indented text
+    #[default]
+    Text,
+    /// One JSON object per event and line
+    Json,
+}
+
+/// The stream [`Backend::Stderr`] writes to on native targets
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Target {
+    #[default]
+    Stderr,
+    /// Written by `println!`, so `cargo test` only shows the output of the failed tests
+    Stdout,
+}
+
+/// Where the output is written to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backend {
+    /// The console API on wasm32-unknown-unknown with the `wasm` feature, stderr otherwise
+    Stderr,
+    /// Every line is emitted by `log::debug!(target: "debug_log", ..)`
+    #[cfg(feature = "log-compat")]
+    Log,
+}
+
+impl Default for Backend {
+    fn default() -> Self {
+        #[cfg(feature = "log-compat")]
+        return Backend::Log;
+        #[cfg(not(feature = "log-compat"))]
+        return Backend::Stderr;
+    }
+}
+
+#[cfg(debug_assertions)]
 mod debug {
-    use std::sync::Mutex;
+    use std::borrow::Cow;
+    use std::cell::RefCell;
+    use std::collections::{HashMap, VecDeque};
+    use std::fs::File;
+    use std::future::Future;
+    use std::io::{BufWriter, Write};
+    use std::marker::PhantomData;
+    use std::path::PathBuf;
+    use std::pin::Pin;
+    use std::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+    use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+    use std::task::{Context, Poll};
+    use std::thread::ThreadId;
+
+    use once_cell::sync::{Lazy, OnceCell};
 
-    use once_cell::sync::Lazy;
+    use crate::{Backend, ColorMode, Format, Target, TimeMode, WasmGroupMode};
+    use filter::Filter;
 
-    static DEBUG: Lazy<Mutex<Option<String>>> =
-        Lazy::new(|| Mutex::new(std::option_env!("DEBUG").map(|x| x.to_owned())));
-    static LEVELS: Mutex<Vec<String>> = Mutex::new(Vec::new());
+    static DEBUG: Lazy<Mutex<Filter>> =
+        Lazy::new(|| Mutex::new(Filter::parse(std::option_env!("DEBUG").unwrap_or(""))));
+    /// Caches whether DEBUG matches nothing or everything, so the common cases don't
+    /// need to lock it. Updated whenever DEBUG changes
+    static DEBUG_STATE: AtomicU8 = AtomicU8::new(STATE_UNKNOWN);
+    static LEVELS: Mutex<Vec<Level>> = Mutex::new(Vec::new());
+    thread_local! {
+        /// The levels of the [`DebugGroup`] being polled on this thread, used instead of
+        /// [`LEVELS`] meanwhile
+        static TASK_LEVELS: RefCell<Option<Vec<Level>>> = const { RefCell::new(None) };
+    }
+    /// Every entry is written while holding this lock, so entries from different
+    /// threads never interleave
+    static SINK: Mutex<Option<Sink>> = Mutex::new(None);
+    static HAS_SINK: AtomicBool = AtomicBool::new(false);
+    /// The recent entries kept to be dumped on panic, instead of being written out
+    static PANIC_BUFFER: Lazy<Mutex<Option<PanicBuffer>>> = Lazy::new(|| {
+        let capacity = std::option_env!("DEBUG_ON_PANIC")
+            .and_then(|x| x.parse::<usize>().ok())
+            .unwrap_or(0);
+        Mutex::new(PanicBuffer::new(capacity))
+    });
+    /// The file the output is appended to instead of stderr
+    static LOG_FILE: Lazy<Mutex<Option<LogFile>>> = Lazy::new(|| {
+        Mutex::new(std::option_env!("DEBUG_FILE").map(|x| LogFile::Pending(PathBuf::from(x))))
+    });
+    static HAS_LOG_FILE: AtomicBool = AtomicBool::new(std::option_env!("DEBUG_FILE").is_some());
+    static WASM_GROUP_MODE: Lazy<Mutex<WasmGroupMode>> = Lazy::new(|| {
+        Mutex::new(match std::option_env!("DEBUG_COLLAPSED") {
+            Some("1") => WasmGroupMode::Collapsed,
+            _ => WasmGroupMode::Expanded,
+        })
+    });
+    static TARGET: Lazy<Mutex<Target>> = Lazy::new(|| {
+        Mutex::new(match std::option_env!("DEBUG_TARGET") {
+            Some("stdout") => Target::Stdout,
+            _ => Target::Stderr,
+        })
+    });
+    static BACKEND: Lazy<Mutex<Backend>> = Lazy::new(|| Mutex::new(Backend::default()));
+    static FORMAT: Lazy<Mutex<Format>> = Lazy::new(|| {
+        Mutex::new(match std::option_env!("DEBUG_FORMAT") {
+            Some("json") => Format::Json,
+            _ => Format::Text,
+        })
+    });
+
+    static TIME_MODE: Lazy<Mutex<TimeMode>> = Lazy::new(|| {
+        Mutex::new(match std::option_env!("DEBUG_TIME") {
+            Some("abs") => TimeMode::Abs,
+            Some("elapsed") => TimeMode::Elapsed,
+            _ => TimeMode::Off,
+        })
+    });
+    static START: OnceCell<f64> = OnceCell::new();
+    /// Whether the closing lines of groups end with their labels
+    static CLOSE_LABELS: Lazy<Mutex<bool>> =
+        Lazy::new(|| Mutex::new(std::option_env!("DEBUG_CLOSE_LABELS") == Some("1")));
+    /// Whether consecutive duplicate entries are collapsed
+    static DEDUP: Lazy<Mutex<bool>> =
+        Lazy::new(|| Mutex::new(std::option_env!("DEBUG_DEDUP") == Some("1")));
+    /// The last entry, its depth and how many times it's repeated since it was written
+    static LAST_ENTRY: Mutex<Option<(String, usize, u64)>> = Mutex::new(None);
+    /// The indentation of a level
+    static INDENT: Lazy<Mutex<String>> = Lazy::new(|| {
+        Mutex::new(match std::option_env!("DEBUG_INDENT") {
+            Some(x) => match x.parse::<usize>() {
+                Ok(n) => " ".repeat(n),
+                Err(_) => x.to_string(),
+            },
+            None => "    ".to_string(),
+        })
+    });
+    static MAX_CELL_WIDTH: Mutex<usize> = Mutex::new(40);
+    /// The max width of a line of `debug_dbg_compact!` before it falls back to the
+    /// pretty format
+    static MAX_LINE_WIDTH: Lazy<Mutex<usize>> = Lazy::new(|| {
+        Mutex::new(
+            std::option_env!("DEBUG_WIDTH")
+                .and_then(|x| x.parse().ok())
+                .unwrap_or(120),
+        )
+    });
+    /// Whether each `group_begin!` that isn't ended yet opened a group
+    static BEGUN: Mutex<Vec<bool>> = Mutex::new(Vec::new());
+    static COUNTS: Lazy<Mutex<HashMap<String, u64>>> = Lazy::new(Default::default);
+    /// The max number of lines and chars of a value printed by `debug_dbg!`
+    static MAX_VALUE_SIZE: Lazy<Mutex<(usize, usize)>> = Lazy::new(|| {
+        let parse = |x: Option<&str>| x.and_then(|x| x.parse().ok()).unwrap_or(usize::MAX);
+        Mutex::new((
+            parse(std::option_env!("DEBUG_MAX_LINES")),
+            parse(std::option_env!("DEBUG_MAX_CHARS")),
+        ))
+    });
+    /// The thread holding [`DebugGuard`]s and how many it holds
+    static SCOPE_OWNER: Mutex<Option<(ThreadId, usize)>> = Mutex::new(None);
+    static SCOPE_RELEASED: Condvar = Condvar::new();
+    static COLOR_MODE: Lazy<Mutex<ColorMode>> = Lazy::new(|| {
+        Mutex::new(match std::option_env!("DEBUG_COLOR") {
+            Some("always") => ColorMode::Always,
+            Some("never") => ColorMode::Never,
+            _ => ColorMode::Auto,
+        })
+    });
+    /// Whether stderr and stdout are colored in the `Auto` mode. It's only checked once,
+    /// as it takes a syscall and reads the environment
+    static STDERR_COLOR: OnceCell<bool> = OnceCell::new();
+    static STDOUT_COLOR: OnceCell<bool> = OnceCell::new();
+
+    const STATE_UNKNOWN: u8 = 0;
+    const STATE_OFF: u8 = 1;
+    const STATE_ALL: u8 = 2;
+    const STATE_CHECK: u8 = 3;
+
+    const GROUP_COLOR: &str = "\x1b[1;34m";
+    const LOCATION_COLOR: &str = "\x1b[90m";
+    const RESET_COLOR: &str = "\x1b[0m";
+    /// The max number of chars of a label on the closing line of its group
+    const MAX_CLOSE_LABEL_CHARS: usize = 40;
+
+    #[doc(hidden)]
+    pub type Sink = Box<dyn FnMut(&str) + Send>;
+
+    #[derive(Clone)]
+    struct Level {
+        label: String,
+        /// Set for timed groups, in [`clock::now_ms`]
+        start: Option<f64>,
+        /// Everything inside is logged, because the group matches `group:<label>`
+        enabled: bool,
+    }
 
-    /// Change the DEBUG value to filter tests
+    /// Change the DEBUG value to filter tests. `set_debug("")` turns the logs off
     pub fn set_debug(s: &str) {
-        *DEBUG.lock().unwrap() = Some(s.to_owned());
+        set_filter(Filter::parse(s));
+    }
+
+    /// Returns the previous filter
+    fn set_filter(filter: Filter) -> Filter {
+        let mut debug = DEBUG.lock().unwrap();
+        DEBUG_STATE.store(filter.state(), Ordering::Relaxed);
+        std::mem::replace(&mut *debug, filter)
+    }
+
+    /// Override the DEBUG value until the returned guard is dropped, then restore the
+    /// previous value, even when unwinding.
+    ///
+    /// Overrides from different threads are serialized: this blocks while another
+    /// thread holds a guard. Nesting guards on the same thread is fine.
+    pub fn with_debug(s: &str) -> DebugGuard {
+        acquire(&SCOPE_OWNER, &SCOPE_RELEASED);
+        let prev = set_filter(Filter::parse(s));
+        DebugGuard {
+            prev,
+            _not_send: PhantomData,
+        }
+    }
+
+    /// Restores the previous DEBUG value on drop. See [`with_debug`]
+    #[must_use = "the previous DEBUG value is restored when the guard is dropped"]
+    pub struct DebugGuard {
+        prev: Filter,
+        // The scope lock is owned by the thread
+        _not_send: PhantomData<*const ()>,
+    }
+
+    impl Drop for DebugGuard {
+        fn drop(&mut self) {
+            set_filter(std::mem::take(&mut self.prev));
+            release(&SCOPE_OWNER, &SCOPE_RELEASED);
+        }
+    }
+
+    /// Wait until no other thread owns `owner`, then own it once more. The same thread
+    /// can own it several times, so nested scopes don't deadlock
+    fn acquire(owner: &Mutex<Option<(ThreadId, usize)>>, released: &Condvar) {
+        let me = std::thread::current().id();
+        let mut owner = owner.lock().unwrap_or_else(|e| e.into_inner());
+        while owner.is_some_and(|(id, _)| id != me) {
+            owner = released.wait(owner).unwrap_or_else(|e| e.into_inner());
+        }
+        owner.get_or_insert((me, 0)).1 += 1;
+    }
+
+    fn release(owner: &Mutex<Option<(ThreadId, usize)>>, released: &Condvar) {
+        let mut owner = owner.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some((_, depth)) = owner.as_mut() {
+            *depth -= 1;
+            if *depth == 0 {
+                *owner = None;
+                released.notify_one();
+            }
+        }
+    }
+
+    /// Change the time prefix of the log entries. It can also be set by
+    /// `DEBUG_TIME=abs` or `DEBUG_TIME=elapsed`
+    pub fn set_time_mode(mode: TimeMode) {
+        *TIME_MODE.lock().unwrap() = mode;
+    }
+
+    /// Change whether the closing lines of groups end with their labels, e.g.
+    /// `} // Sub A Group`. It can also be set by `DEBUG_CLOSE_LABELS=1`
+    pub fn set_close_labels(enabled: bool) {
+        *CLOSE_LABELS.lock().unwrap() = enabled;
+    }
+
+    /// Change whether consecutive duplicate entries are collapsed into
+    /// `... last message repeated N times`. Entries are duplicates when they're the same
+    /// including their `[file:line]` prefixes, and a group boundary in between ends the
+    /// run. It can also be set by `DEBUG_DEDUP=1`
+    pub fn set_dedup(enabled: bool) {
+        let mut sink = SINK.lock().unwrap();
+        flush_repeats(&mut sink);
+        *DEDUP.lock().unwrap() = enabled;
+    }
+
+    /// Limit the size of the values printed by `debug_dbg!`. Longer values are
+    /// truncated with a marker. The first and the last line of a value are always kept
+    /// when it's cut by lines, so a limit below 2 lines means 2. It can also be set by
+    /// `DEBUG_MAX_LINES` and `DEBUG_MAX_CHARS`
+    pub fn set_max_value_size(lines: usize, chars: usize) {
+        *MAX_VALUE_SIZE.lock().unwrap() = (lines, chars);
+    }
+
+    /// Change the indentation of a level, e.g. `"  "`, `"\t"` or `"│   "`. It's four spaces
+    /// by default. It can also be set by `DEBUG_INDENT`, whose value is either the
+    /// indentation or the number of spaces
+    pub fn set_indent(indent: &str) {
+        *INDENT.lock().unwrap() = indent.to_string();
+    }
+
+    /// Change the max width of a line of `debug_dbg_compact!`, including the indentation
+    /// and the prefix. Longer values are printed like `debug_dbg!`. It's 120 by default.
+    /// It can also be set by `DEBUG_WIDTH`
+    pub fn set_max_line_width(width: usize) {
+        *MAX_LINE_WIDTH.lock().unwrap() = width;
+    }
+
+    /// Limit the width of the cells of `debug_table!`. Longer cells are truncated with `…`
+    pub fn set_max_cell_width(width: usize) {
+        *MAX_CELL_WIDTH.lock().unwrap() = width;
+    }
+
+    /// Change whether the output is colored. It can also be set by
+    /// `DEBUG_COLOR=always|never|auto`
+    pub fn set_color(mode: ColorMode) {
+        *COLOR_MODE.lock().unwrap() = mode;
+    }
+
+    fn use_color() -> bool {
+        if cfg!(all(target_arch = "wasm32", target_os = "unknown")) {
+            // The browser console doesn't understand ANSI escape codes
+            return false;
+        }
+
+        match *COLOR_MODE.lock().unwrap() {
+            ColorMode::Always => true,
+            ColorMode::Never => false,
+            ColorMode::Auto => {
+                use std::io::IsTerminal;
+                // The records of the `log` backend end up in files more often than not
+                !HAS_SINK.load(Ordering::Relaxed)
+                    && !HAS_LOG_FILE.load(Ordering::Relaxed)
+                    && *BACKEND.lock().unwrap() == Backend::Stderr
+                    && match *TARGET.lock().unwrap() {
+                        Target::Stderr => *STDERR_COLOR.get_or_init(|| {
+                            std::env::var_os("NO_COLOR").is_none()
+                                && std::io::stderr().is_terminal()
+                        }),
+                        Target::Stdout => *STDOUT_COLOR.get_or_init(|| {
+                            std::env::var_os("NO_COLOR").is_none()
+                                && std::io::stdout().is_terminal()
+                        }),
+                    }
+            }
+        }
+    }
+
+    fn paint<'a>(s: &'a str, color: &str) -> Cow<'a, str> {
+        if use_color() {
+            Cow::Owned(format!("{}{}{}", color, s, RESET_COLOR))
+        } else {
+            Cow::Borrowed(s)
+        }
+    }
+
+    /// The indentation and the `[file:line] ` prefix of an entry
+    #[doc(hidden)]
+    pub fn log_prefix(line: &str) -> String {
+        prefix_at(line, get_level())
+    }
+
+    fn prefix_at(line: &str, depth: usize) -> String {
+        format!(
+            "{}[{}] ",
+            indentation(depth),
+            paint(&normalize_path(line), LOCATION_COLOR)
+        )
+    }
+
+    /// `file!()` uses backslashes on Windows. Use forward slashes everywhere, so the
+    /// filters and the output are the same on all platforms
+    pub(crate) fn normalize_path(path: &str) -> Cow<'_, str> {
+        if path.contains('\\') {
+            Cow::Owned(path.replace('\\', "/"))
+        } else {
+            Cow::Borrowed(path)
+        }
+    }
+
+    /// Change whether groups are collapsed in the browser console. It can also be set by
+    /// `DEBUG_COLLAPSED=1`
+    pub fn set_wasm_group_mode(mode: WasmGroupMode) {
+        *WASM_GROUP_MODE.lock().unwrap() = mode;
+    }
+
+    /// Change the format of the output. It can also be set by `DEBUG_FORMAT=json`
+    pub fn set_format(format: Format) {
+        *FORMAT.lock().unwrap() = format;
+    }
+
+    /// Change where the output is written to
+    pub fn set_backend(backend: Backend) {
+        *BACKEND.lock().unwrap() = backend;
+    }
+
+    /// Change whether the output is written to stderr or stdout. It can also be set by
+    /// `DEBUG_TARGET=stdout`. It doesn't affect the console API on wasm
+    pub fn set_target(target: Target) {
+        *TARGET.lock().unwrap() = target;
+    }
+
+    /// Redirect all the output to `sink`, or back to the console if it's `None`.
+    /// Returns the previous sink.
+    #[doc(hidden)]
+    pub fn set_sink(sink: Option<Sink>) -> Option<Sink> {
+        let mut lock = SINK.lock().unwrap();
+        flush_repeats(&mut lock);
+        HAS_SINK.store(sink.is_some(), Ordering::Relaxed);
+        std::mem::replace(&mut *lock, sink)
+    }
+
+    enum LogFile {
+        /// The file is created on the first write
+        Pending(PathBuf),
+        Open(BufWriter<File>),
+        /// The file couldn't be opened, so the output goes to stderr
+        Failed,
+    }
+
+    /// Append all the output to the file at `path` instead of writing it to the backend,
+    /// or go back to the backend if it's `None`. The file is created on the first write. It can
+    /// also be set by `DEBUG_FILE`
+    pub fn set_log_file(path: Option<PathBuf>) {
+        let _sink = SINK.lock().unwrap();
+        let mut file = LOG_FILE.lock().unwrap();
+        if let Some(LogFile::Open(w)) = file.as_mut() {
+            let _ = w.flush();
+        }
+        HAS_LOG_FILE.store(path.is_some(), Ordering::Relaxed);
+        *file = path.map(LogFile::Pending);
+    }
+
+    /// Returns false if the entry should be written to stderr instead
+    fn write_log_file(s: &str) -> bool {
+        if !HAS_LOG_FILE.load(Ordering::Relaxed) {
+            return false;
+        }
+
+        let mut file = LOG_FILE.lock().unwrap();
+        if let Some(LogFile::Pending(path)) = file.as_ref() {
+            *file = Some(match File::options().create(true).append(true).open(path) {
+                Ok(f) => {
+                    flush_at_exit();
+                    LogFile::Open(BufWriter::new(f))
+                }
+                Err(err) => {
+                    eprintln!(
+                        "debug-log: failed to open {}: {}. Writing to stderr instead",
+                        path.display(),
+                        err
+                    );
+                    LogFile::Failed
+                }
+            });
+        }
+
+        match file.as_mut() {
+            Some(LogFile::Open(w)) => {
+                let mut buf = String::with_capacity(s.len() + 1);
+                buf.push_str(s);
+                buf.push('\n');
+                w.write_all(buf.as_bytes()).is_ok()
+            }
+            _ => false,
+        }
+    }
+
+    fn flush_log_file() {
+        if !HAS_LOG_FILE.load(Ordering::Relaxed) {
+            return;
+        }
+
+        if let Some(LogFile::Open(w)) = LOG_FILE.lock().unwrap().as_mut() {
+            let _ = w.flush();
+        }
+    }
+
+    /// Statics are never dropped, so the buffered output left by a group that is still
+    /// open and the pending repeat count are flushed by an `atexit` hook
+    fn flush_at_exit() {
+        #[cfg(any(unix, windows))]
+        {
+            extern "C" fn flush() {
+                // Best effort. Don't block the exit if another thread is writing
+                if let Ok(mut sink) = SINK.try_lock() {
+                    flush_repeats(&mut sink);
+                }
+                if let Ok(mut file) = LOG_FILE.try_lock() {
+                    if let Some(LogFile::Open(w)) = file.as_mut() {
+                        let _ = w.flush();
+                    }
+                }
+            }
+
+            extern "C" {
+                fn atexit(f: extern "C" fn()) -> std::os::raw::c_int;
+            }
+
+            static REGISTER: std::sync::Once = std::sync::Once::new();
+            REGISTER.call_once(|| unsafe {
+                atexit(flush);
+            });
+        }
+    }
+
+    struct PanicBuffer {
+        entries: VecDeque<String>,
+        capacity: usize,
+        /// Whether older entries were dropped to make room
+        truncated: bool,
+    }
+
+    impl PanicBuffer {
+        fn new(capacity: usize) -> Option<Self> {
+            if capacity == 0 {
+                return None;
+            }
+
+            install_panic_hook();
+            Some(PanicBuffer {
+                entries: VecDeque::with_capacity(capacity),
+                capacity,
+                truncated: false,
+            })
+        }
+
+        fn push(&mut self, s: &str) {
+            if self.entries.len() == self.capacity {
+                self.entries.pop_front();
+                self.truncated = true;
+            }
+            self.entries.push_back(s.to_string());
+        }
+    }
+
+    /// Keep only the last `capacity` entries instead of writing them out, and write them
+    /// out when a panic happens, right before the panic message. Only the entries that
+    /// pass the DEBUG filter are kept. Set `capacity` to 0 to write the entries out as
+    /// usual. It can also be set by `DEBUG_ON_PANIC`
+    pub fn enable_panic_dump(capacity: usize) {
+        let _sink = SINK.lock().unwrap();
+        *PANIC_BUFFER.lock().unwrap() = PanicBuffer::new(capacity);
     }
 
+    fn install_panic_hook() {
+        static INSTALL: std::sync::Once = std::sync::Once::new();
+        INSTALL.call_once(|| {
+            let prev = std::panic::take_hook();
+            std::panic::set_hook(Box::new(move |info| {
+                dump_panic_buffer();
+                prev(info);
+            }));
+        });
+    }
+
+    fn dump_panic_buffer() {
+        // The panic may happen while the locks are held by this thread, so don't
+        // wait for them
+        let (entries, truncated) = match PANIC_BUFFER.try_lock() {
+            Ok(mut buffer) => match buffer.as_mut() {
+                Some(buffer) => (
+                    std::mem::take(&mut buffer.entries),
+                    std::mem::replace(&mut buffer.truncated, false),
+                ),
+                None => return,
+            },
+            Err(_) => return,
+        };
+        if entries.is_empty() {
+            return;
+        }
+
+        let mut dump = String::new();
+        if truncated {
+            dump.push_str(&render(Event::Truncated, 0));
+            dump.push('\n');
+        }
+        for entry in entries.iter() {
+            dump.push_str(entry);
+            dump.push('\n');
+        }
+        dump.pop();
+        match SINK.try_lock() {
+            Ok(mut sink) => write_out(&mut sink, &dump),
+            Err(_) => eprintln!("{}", dump),
+        }
+        flush_log_file();
+    }
+
+    fn time_prefix() -> String {
+        let start = *START.get_or_init(clock::now_ms);
+        match *TIME_MODE.lock().unwrap() {
+            TimeMode::Off => String::new(),
+            TimeMode::Abs => {
+                let ms = clock::epoch_ms() as u64;
+                format!(
+                    "{:02}:{:02}:{:02}.{:03} ",
+                    ms / 3_600_000 % 24,
+                    ms / 60_000 % 60,
+                    ms / 1000 % 60,
+                    ms % 1000
+                )
+            }
+            TimeMode::Elapsed => format!("{:>10.3}ms ", clock::now_ms() - start),
+        }
+    }
+
+    /// Add the time as a field of the JSON object
+    fn json_with_time(s: &str) -> Cow<'_, str> {
+        let time = time_prefix();
+        if time.is_empty() {
+            return Cow::Borrowed(s);
+        }
+
+        Cow::Owned(format!(
+            "{{\"time\":{},{}",
+            json_string(time.trim()),
+            &s[1..]
+        ))
+    }
+
+    /// Prefix the first line with the time, and pad the other lines so they stay
+    /// aligned with it
+    fn with_time_prefix(s: &str) -> Cow<'_, str> {
+        let prefix = time_prefix();
+        if prefix.is_empty() {
+            return Cow::Borrowed(s);
+        }
+
+        let padding = " ".repeat(prefix.chars().count());
+        let mut ans = String::with_capacity(s.len() + prefix.len());
+        for (i, line) in s.split('\n').enumerate() {
+            if i == 0 {
+                ans.push_str(&prefix);
+            } else {
+                ans.push('\n');
+                ans.push_str(&padding);
+            }
+            ans.push_str(line);
+        }
+        Cow::Owned(ans)
+    }
+
+    /// Run `f` and return everything it logged instead of writing it out. It doesn't
+    /// change the DEBUG filter.
+    ///
+    /// Captures from different threads are serialized, but logs from other threads
+    /// made during a capture are captured as well. A capture nested in another one on
+    /// the same thread takes the output logged inside it.
+    pub fn capture(f: impl FnOnce()) -> String {
+        /// The thread running captures and how many it runs
+        static CAPTURE_OWNER: Mutex<Option<(ThreadId, usize)>> = Mutex::new(None);
+        static CAPTURE_RELEASED: Condvar = Condvar::new();
+        struct Restore(Option<Option<Sink>>);
+        impl Drop for Restore {
+            fn drop(&mut self) {
+                set_sink(self.0.take().unwrap());
+                release(&CAPTURE_OWNER, &CAPTURE_RELEASED);
+            }
+        }
+
+        acquire(&CAPTURE_OWNER, &CAPTURE_RELEASED);
+        let buffer = Arc::new(Mutex::new(String::new()));
+        let cloned = buffer.clone();
+        let _restore = Restore(Some(set_sink(Some(Box::new(move |s: &str| {
+            let mut buffer = cloned.lock().unwrap();
+            buffer.push_str(s);
+            buffer.push('\n');
+        })))));
+        f();
+        let ans = std::mem::take(&mut *buffer.lock().unwrap());
+        ans
+    }
+
+    fn write_locked(sink: &mut MutexGuard<Option<Sink>>, s: &str) {
+        let s = &if *FORMAT.lock().unwrap() == Format::Json {
+            json_with_time(s)
+        } else {
+            with_time_prefix(s)
+        };
+        if let Some(buffer) = PANIC_BUFFER.lock().unwrap().as_mut() {
+            buffer.push(s);
+            return;
+        }
+
+        write_out(sink, s);
+    }
+
+    fn write_out(sink: &mut MutexGuard<Option<Sink>>, s: &str) {
+        if let Some(f) = sink.as_mut() {
+            f(s);
+        } else if !write_log_file(s) {
+            match *BACKEND.lock().unwrap() {
+                Backend::Stderr => console::log(s),
+                #[cfg(feature = "log-compat")]
+                Backend::Log => {
+                    for line in s.split('\n') {
+                        log::debug!(target: "debug_log", "{}", line);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Write a whole log entry at once. It may contain multiple lines.
+    #[doc(hidden)]
+    pub fn emit(s: &str) {
+        let mut sink = SINK.lock().unwrap();
+        if *DEDUP.lock().unwrap() {
+            let depth = with_levels(|levels| levels.len());
+            let mut last = LAST_ENTRY.lock().unwrap();
+            match last.as_mut() {
+                Some((entry, _, count)) if entry == s => {
+                    *count += 1;
+                    return;
+                }
+                _ => {
+                    if let Some((_, depth, count)) = last.take() {
+                        write_repeats(&mut sink, depth, count);
+                    }
+                    *last = Some((s.to_string(), depth, 0));
+                    flush_at_exit();
+                }
+            }
+        }
+        write_locked(&mut sink, s);
+        if HAS_LOG_FILE.load(Ordering::Relaxed) && with_levels(|levels| levels.is_empty()) {
+            flush_log_file();
+        }
+    }
+
+    /// Write the pending repeat count and forget the last entry
+    fn flush_repeats(sink: &mut MutexGuard<Option<Sink>>) {
+        if let Some((_, depth, count)) = LAST_ENTRY.lock().unwrap().take() {
+            write_repeats(sink, depth, count);
+        }
+    }
+
+    fn write_repeats(sink: &mut MutexGuard<Option<Sink>>, depth: usize, count: u64) {
+        if count > 0 {
+            write_locked(sink, &render(Event::Repeated { count }, depth));
+        }
+    }
+
+    mod clock {
+        /// Milliseconds since the unix epoch
+        #[cfg(all(feature = "wasm", target_arch = "wasm32", target_os = "unknown"))]
+        pub fn epoch_ms() -> f64 {
+            js_sys::Date::now()
+        }
+
+        #[cfg(all(feature = "wasm", target_arch = "wasm32", target_os = "unknown"))]
+        #[wasm_bindgen::prelude::wasm_bindgen]
+        extern "C" {
+            #[wasm_bindgen(js_namespace = performance, js_name = now)]
+            fn performance_now() -> f64;
+        }
+
+        /// Monotonic milliseconds
+        #[cfg(all(feature = "wasm", target_arch = "wasm32", target_os = "unknown"))]
+        pub fn now_ms() -> f64 {
+            performance_now()
+        }
+
+        /// There's no clock on wasm32-unknown-unknown without JS
+        #[cfg(all(not(feature = "wasm"), target_arch = "wasm32", target_os = "unknown"))]
+        pub fn epoch_ms() -> f64 {
+            0.
+        }
+
+        #[cfg(all(not(feature = "wasm"), target_arch = "wasm32", target_os = "unknown"))]
+        pub fn now_ms() -> f64 {
+            0.
+        }
+
+        /// Milliseconds since the unix epoch
+        #[cfg(not(all(target_arch = "wasm32", target_os = "unknown")))]
+        pub fn epoch_ms() -> f64 {
+            std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .map_or(0., |d| d.as_secs_f64() * 1000.)
+        }
+
+        /// Monotonic milliseconds
+        #[cfg(not(all(target_arch = "wasm32", target_os = "unknown")))]
+        pub fn now_ms() -> f64 {
+            static ORIGIN: once_cell::sync::Lazy<std::time::Instant> =
+                once_cell::sync::Lazy::new(std::time::Instant::now);
+            ORIGIN.elapsed().as_secs_f64() * 1000.
+        }
+    }
+
+    /// The console API with `wasm-bindgen` on wasm32-unknown-unknown. Everywhere else,
+    /// including WASI and wasm32 without the `wasm` feature, the entries are written to
+    /// stderr and the groups are rendered as indented text
     pub mod console {
-        #[cfg(all(feature = "wasm", target_arch = "wasm32"))]
+        #[cfg(all(feature = "wasm", target_arch = "wasm32", target_os = "unknown"))]
         use wasm_bindgen::prelude::wasm_bindgen;
 
-        #[cfg(all(feature = "wasm", target_arch = "wasm32"))]
+        #[cfg(all(feature = "wasm", target_arch = "wasm32", target_os = "unknown"))]
         #[wasm_bindgen]
         extern "C" {
             // Use `js_namespace` here to bind `console.log(..)` instead of just
@@ -96,22 +917,39 @@ mod debug {
             #[wasm_bindgen(js_namespace = console)]
             pub fn group(s: &str);
 
+            #[wasm_bindgen(js_namespace = console)]
+            pub fn groupCollapsed(s: &str);
+
             #[wasm_bindgen(js_namespace = console)]
             pub fn groupEnd();
         }
 
-        #[cfg(not(all(feature = "wasm", target_arch = "wasm32")))]
+        #[cfg(not(all(feature = "wasm", target_arch = "wasm32", target_os = "unknown")))]
         pub use patch::*;
-        #[cfg(not(all(feature = "wasm", target_arch = "wasm32")))]
+        #[cfg(not(all(feature = "wasm", target_arch = "wasm32", target_os = "unknown")))]
         mod patch {
+            use crate::Target;
+
+            /// `eprintln!` holds the lock of stderr while writing the entry and its line
+            /// break, so it can't be split by other writers. Unlike writing to
+            /// `io::stderr()` directly, it's captured by the test harness
             pub fn log(s: &str) {
-                eprintln!("{}", s);
+                match *super::super::TARGET.lock().unwrap() {
+                    Target::Stderr => eprintln!("{}", s),
+                    Target::Stdout => println!("{}", s),
+                }
             }
 
             pub fn group(s: &str) {
-                eprintln!("{}", s);
+                log(s);
             }
 
+            #[allow(non_snake_case)]
+            pub fn groupCollapsed(s: &str) {
+                log(s);
+            }
+
+            #[allow(non_snake_case)]
             pub fn groupEnd() {}
         }
     }
@@ -120,210 +958,3204 @@ mod debug {
     #[macro_export]
     macro_rules! inner_println {
         ($($arg:tt)+) => {{
-            if $crate::should_log(&file!()) {
-                if cfg!(all(feature = "wasm", target_arch = "wasm32")) {
-                    let s = format!($($arg)+);
-                    $crate::console::log(&s);
-                } else {
-                    eprintln!($($arg)+);
-                }
+            if $crate::should_log(file!(), line!(), module_path!()) {
+                $crate::emit(&format!($($arg)+));
             }
         }};
         () => {
-            if $crate::should_log(&file!()) {
-                if cfg!(all(feature = "wasm", target_arch = "wasm32")) {
-                    $crate::console::log("");
-                } else {
-                    eprintln!();
-                }
+            if $crate::should_log(file!(), line!(), module_path!()) {
+                $crate::emit("");
             }
         };
     }
 
     #[doc(hidden)]
     pub fn get_level() -> usize {
-        LEVELS.lock().unwrap().len()
+        with_levels(|levels| levels.len())
+    }
+
+    /// Run `f` with the levels of the task polled on this thread, or the global ones
+    fn with_levels<R>(f: impl FnOnce(&mut Vec<Level>) -> R) -> R {
+        let mut f = Some(f);
+        // The thread locals may be gone when logging from a destructor at thread exit
+        let ans = TASK_LEVELS.try_with(|task| {
+            let mut task = task.borrow_mut();
+            task.as_mut().map(|levels| f.take().unwrap()(levels))
+        });
+        match ans {
+            Ok(Some(ans)) => ans,
+            _ => f.take().unwrap()(&mut LEVELS.lock().unwrap()),
+        }
     }
 
+    /// The header and the level change happen under the output lock, so another
+    /// thread can't log between them
     #[doc(hidden)]
     pub fn indent(name: &str) {
-        let space = format!("{}", "    ".repeat(get_level()));
-        inner_println!("{}{} {{", space, name);
-        LEVELS.lock().unwrap().push(name.to_string())
+        push_level(name, false);
+    }
+
+    fn push_level(name: &str, timed: bool) {
+        let mut sink = SINK.lock().unwrap();
+        flush_repeats(&mut sink);
+        let (depth, parent_enabled) =
+            with_levels(|levels| (levels.len(), levels.last().is_some_and(|x| x.enabled)));
+        if uses_console_groups() {
+            let name = with_time_prefix(name);
+            match *WASM_GROUP_MODE.lock().unwrap() {
+                WasmGroupMode::Expanded => console::group(&name),
+                WasmGroupMode::Collapsed => console::groupCollapsed(&name),
+            }
+        } else {
+            write_locked(&mut sink, &render(Event::GroupStart { label: name }, depth));
+        }
+        let enabled = parent_enabled || DEBUG.lock().unwrap().matches_group(name);
+        with_levels(|levels| {
+            levels.push(Level {
+                label: name.to_string(),
+                start: timed.then(clock::now_ms),
+                enabled,
+            })
+        })
     }
 
     #[doc(hidden)]
     pub fn outdent() {
-        LEVELS.lock().unwrap().pop();
-        let space = format!("{}", "    ".repeat(get_level()));
-        inner_println!("{}}}", space);
+        pop_level(None);
     }
 
-    #[doc(hidden)]
-    pub fn dbg<T: std::fmt::Debug>(value: T, name: &str, line: &str) {
-        let s = format!("{:#?}", value);
-        let mut ans = String::new();
-        ans.push_str(&"    ".repeat(get_level()));
-        ans.push_str(format!("[{}] {} = ", line, name).as_str());
-        for (i, line) in s.split('\n').enumerate() {
-            if i != 0 {
-                ans.push_str(&"    ".repeat(get_level()));
+    /// `expected` is the label passed to `group_end!` and its location
+    fn pop_level(expected: Option<(&str, &str)>) {
+        let mut sink = SINK.lock().unwrap();
+        flush_repeats(&mut sink);
+        let (level, depth) = with_levels(|levels| (levels.pop(), levels.len()));
+        if let (Some(level), Some((label, line))) = (&level, expected) {
+            if level.label != label {
+                let msg = format!(
+                    "warning: group_end!({:?}) closed the group {:?}",
+                    label, level.label
+                );
+                write_locked(
+                    &mut sink,
+                    &render(Event::Log { line, msg: &msg }, depth + 1),
+                );
             }
-            ans.push_str(line);
-            ans.push('\n')
         }
-
-        if ans.ends_with('\n') {
-            ans.drain(ans.len() - 1..);
+        let elapsed = level
+            .as_ref()
+            .and_then(|x| x.start)
+            .map(|start| format_duration(clock::now_ms() - start));
+        if uses_console_groups() {
+            if let Some(elapsed) = elapsed {
+                write_locked(&mut sink, &format!("// {}", elapsed));
+            }
+            console::groupEnd();
+            return;
         }
 
-        inner_println!("{}", ans);
+        let label = level
+            .filter(|_| *CLOSE_LABELS.lock().unwrap())
+            .map(|x| close_label(&x.label));
+        write_locked(
+            &mut sink,
+            &render(
+                Event::GroupEnd {
+                    label: label.as_deref(),
+                    elapsed: elapsed.as_deref(),
+                },
+                depth,
+            ),
+        );
+        if depth == 0 {
+            flush_log_file();
+        }
     }
 
-    #[doc(hidden)]
-    pub fn prepend_indent(s: String) -> String {
-        let mut ans = String::new();
-        for (i, line) in s.split('\n').enumerate() {
-            if i != 0 {
-                ans.push_str(&"    ".repeat(get_level()));
-            }
-            ans.push_str(line);
-            ans.push('\n')
+    /// The first line of the label, cut down to [`MAX_CLOSE_LABEL_CHARS`]
+    fn close_label(label: &str) -> String {
+        let line = label.lines().next().unwrap_or("");
+        if line.len() == label.len() && line.chars().count() <= MAX_CLOSE_LABEL_CHARS {
+            return line.to_string();
         }
+
+        let mut ans: String = line.chars().take(MAX_CLOSE_LABEL_CHARS - 1).collect();
+        ans.push('…');
         ans
     }
 
-    #[doc(hidden)]
-    pub fn should_log(file: &str) -> bool {
-        let lock = DEBUG.lock().unwrap();
-        lock.as_ref()
-            .map_or(false, |x| !x.is_empty() && (x == "*" || file.contains(x)))
+    /// A log entry, rendered by [`render`] in the current [`Format`]
+    enum Event<'a> {
+        Log {
+            line: &'a str,
+            msg: &'a str,
+        },
+        Dbg {
+            line: &'a str,
+            name: &'a str,
+            value: &'a str,
+        },
+        GroupStart {
+            label: &'a str,
+        },
+        /// `label` is only set when [`set_close_labels`] is on
+        GroupEnd {
+            label: Option<&'a str>,
+            elapsed: Option<&'a str>,
+        },
+        /// The previous entry is repeated `count` more times. See [`set_dedup`]
+        Repeated {
+            count: u64,
+        },
+        /// The panic dump dropped the entries before it. See [`enable_panic_dump`]
+        Truncated,
     }
 
-    /// Group the following logs until the guard is dropped
-    #[macro_export]
-    macro_rules! group {
-        ($($arg:tt)*) => {
-            let __debug_log_group_guard = {
-                let line = format!("{}:{}", file!(), line!());
-                let mut guard = None;
-                if $crate::should_log(&line) {
-                    $crate::indent(&format!($($arg)*));
-                    guard = Some($crate::GroupGuard);
-                }
-                guard
-            };
-        };
-        () => {
-            let mut __debug_log_group_guard= None;
-            if $crate::should_log(&file!()) {
-                $crate::indent("".to_string());
-                __debug_log_group_guard = Some($crate::GroupGuard);
-            }
-        };
-    }
+    /// `depth` is the number of the groups containing the event
+    fn render(event: Event, depth: usize) -> String {
+        if *FORMAT.lock().unwrap() == Format::Json {
+            return render_json(event, depth);
+        }
 
-    #[doc(hidden)]
-    pub struct GroupGuard;
-    impl Drop for GroupGuard {
-        fn drop(&mut self) {
-            crate::outdent();
+        match event {
+            Event::Log { line, msg } => {
+                format!("{}{}", prefix_at(line, depth), indent_lines(msg, depth))
+            }
+            Event::Dbg { line, name, value } => format!(
+                "{}{} = {}",
+                prefix_at(line, depth),
+                name,
+                indent_lines(value, depth)
+            ),
+            Event::GroupStart { label } => {
+                format!("{}{} {{", indentation(depth), paint(label, GROUP_COLOR))
+            }
+            Event::GroupEnd { label, elapsed } => {
+                let comment = match (label, elapsed) {
+                    (None, None) => String::new(),
+                    (Some(x), None) | (None, Some(x)) => format!(" // {}", x),
+                    (Some(label), Some(elapsed)) => format!(" // {}, {}", label, elapsed),
+                };
+                format!("{}}}{}", indentation(depth), comment)
+            }
+            Event::Repeated { count } => format!(
+                "{}... last message repeated {} {}",
+                indentation(depth),
+                count,
+                if count == 1 { "time" } else { "times" }
+            ),
+            Event::Truncated => "... earlier output truncated".to_string(),
         }
     }
 
-    /// It can be filtered by DEBUG env and can only log on debug mode
-    #[macro_export]
-    macro_rules! debug_dbg {
-        ($($val:expr),+ $(,)?) => {
-            let line = format!("{}:{}", file!(), line!());
-            if $crate::should_log(&line) {
-                ($($crate::dbg($val, stringify!($val), &line)),+,);
+    fn render_json(event: Event, depth: usize) -> String {
+        let location = |line: &str| {
+            let (file, line) = line.rsplit_once(':').unwrap_or((line, "0"));
+            format!(
+                "\"file\":{},\"line\":{}",
+                json_string(&normalize_path(file)),
+                line
+            )
+        };
+        match event {
+            Event::Log { line, msg } => format!(
+                "{{\"type\":\"log\",{},\"depth\":{},\"msg\":{}}}",
+                location(line),
+                depth,
+                json_string(msg)
+            ),
+            Event::Dbg { line, name, value } => format!(
+                "{{\"type\":\"dbg\",{},\"depth\":{},\"name\":{},\"value\":{}}}",
+                location(line),
+                depth,
+                json_string(name),
+                json_string(value)
+            ),
+            Event::GroupStart { label } => format!(
+                "{{\"type\":\"group_start\",\"depth\":{},\"label\":{}}}",
+                depth,
+                json_string(label)
+            ),
+            Event::GroupEnd { label, elapsed } => {
+                let mut s = format!("{{\"type\":\"group_end\",\"depth\":{}", depth);
+                if let Some(label) = label {
+                    s.push_str(&format!(",\"label\":{}", json_string(label)));
+                }
+                if let Some(elapsed) = elapsed {
+                    s.push_str(&format!(",\"elapsed\":{}", json_string(elapsed)));
+                }
+                s.push('}');
+                s
+            }
+            Event::Repeated { count } => format!(
+                "{{\"type\":\"repeated\",\"depth\":{},\"count\":{}}}",
+                depth, count
+            ),
+            Event::Truncated => "{\"type\":\"truncated\"}".to_string(),
+        }
+    }
+
+    fn json_string(s: &str) -> String {
+        let mut ans = String::with_capacity(s.len() + 2);
+        ans.push('"');
+        for c in s.chars() {
+            match c {
+                '"' => ans.push_str("\\\""),
+                '\\' => ans.push_str("\\\\"),
+                '\n' => ans.push_str("\\n"),
+                '\r' => ans.push_str("\\r"),
+                '\t' => ans.push_str("\\t"),
+                c if (c as u32) < 0x20 => ans.push_str(&format!("\\u{:04x}", c as u32)),
+                c => ans.push(c),
+            }
+        }
+        ans.push('"');
+        ans
+    }
+
+    /// Whether groups are rendered by the console API. The console indents grouped
+    /// entries by itself, so there's no need to indent them manually.
+    fn uses_console_groups() -> bool {
+        cfg!(all(
+            feature = "wasm",
+            target_arch = "wasm32",
+            target_os = "unknown"
+        )) && !HAS_SINK.load(Ordering::Relaxed)
+            && !HAS_LOG_FILE.load(Ordering::Relaxed)
+            && *BACKEND.lock().unwrap() == Backend::Stderr
+            && *FORMAT.lock().unwrap() == Format::Text
+    }
+
+    fn indentation(level: usize) -> String {
+        if uses_console_groups() {
+            String::new()
+        } else {
+            INDENT.lock().unwrap().repeat(level)
+        }
+    }
+
+    pub(crate) fn format_duration(ms: f64) -> String {
+        if ms < 1. {
+            format!("{:.1}µs", ms * 1000.)
+        } else if ms < 1000. {
+            format!("{:.1}ms", ms)
+        } else {
+            format!("{:.2}s", ms / 1000.)
+        }
+    }
+
+    #[doc(hidden)]
+    pub fn dbg<T: std::fmt::Debug + ?Sized>(value: &T, name: &str, line: &str) {
+        emit_dbg(&format!("{:#?}", value), name, line, get_level());
+    }
+
+    /// Like [`dbg`], but the value is on one line unless the line would be wider than
+    /// [`set_max_line_width`]
+    #[doc(hidden)]
+    pub fn dbg_compact<T: std::fmt::Debug + ?Sized>(value: &T, name: &str, line: &str) {
+        let depth = get_level();
+        let s = format!("{:?}", value);
+        // `[line] name = value`
+        let width = indentation(depth).chars().count()
+            + normalize_path(line).chars().count()
+            + name.chars().count()
+            + s.chars().count()
+            + 6;
+        if width <= *MAX_LINE_WIDTH.lock().unwrap() {
+            emit_dbg(&s, name, line, depth);
+        } else {
+            emit_dbg(&format!("{:#?}", value), name, line, depth);
+        }
+    }
+
+    fn emit_dbg(s: &str, name: &str, line: &str, depth: usize) {
+        let (max_lines, max_chars) = *MAX_VALUE_SIZE.lock().unwrap();
+        let s = truncate_value(s, max_lines, max_chars);
+        emit(&render(
+            Event::Dbg {
+                line,
+                name,
+                value: &s,
+            },
+            depth,
+        ));
+    }
+
+    /// Cut the formatted value down to `max_lines` lines and `max_chars` chars, not
+    /// counting the marker of how much was cut. The first and the last line are kept,
+    /// so the brackets are still balanced and fewer than 2 lines means 2. A value that
+    /// is still too long, e.g. a single line, is cut after `max_chars` chars instead.
+    pub(crate) fn truncate_value(s: &str, max_lines: usize, max_chars: usize) -> Cow<'_, str> {
+        let max_lines = max_lines.max(2);
+        let lines: Vec<&str> = s.split('\n').collect();
+        let chars = s.chars().count();
+        if lines.len() <= max_lines && chars <= max_chars {
+            return Cow::Borrowed(s);
+        }
+
+        if lines.len() > 2 {
+            let last = lines[lines.len() - 1];
+            // The chars of the lines kept, including their line breaks
+            let mut used = last.chars().count();
+            let mut keep = 0;
+            for line in lines[..lines.len() - 1].iter().take(max_lines - 1) {
+                let n = line.chars().count() + 1;
+                if keep > 0 && used + n > max_chars {
+                    break;
+                }
+                used += n;
+                keep += 1;
+            }
+
+            let omitted = lines.len() - 1 - keep;
+            if omitted > 0 && used <= max_chars {
+                let next = lines[keep];
+                let marker_indent = &next[..next.len() - next.trim_start().len()];
+                let mut ans = lines[..keep].join("\n");
+                ans.push_str(&format!(
+                    "\n{}... ({} more {} truncated)\n{}",
+                    marker_indent,
+                    omitted,
+                    if omitted == 1 { "line" } else { "lines" },
+                    last
+                ));
+                return Cow::Owned(ans);
+            }
+        }
+
+        // Only the chars are over the limit here
+        let kept: String = s.chars().take(max_chars).collect();
+        let omitted = chars - max_chars;
+        Cow::Owned(format!(
+            "{}... ({} more {} truncated)",
+            kept,
+            omitted,
+            if omitted == 1 { "char" } else { "chars" }
+        ))
+    }
+
+    #[doc(hidden)]
+    pub fn prepend_indent(s: String) -> String {
+        indent_lines(&s, get_level())
+    }
+
+    /// Indent all the lines but the first one
+    fn indent_lines(s: &str, depth: usize) -> String {
+        let mut ans = String::new();
+        for (i, line) in s.split('\n').enumerate() {
+            if i != 0 {
+                ans.push('\n');
+                ans.push_str(&indentation(depth));
+            }
+            ans.push_str(line);
+        }
+        ans
+    }
+
+    /// Logs are enabled if the location or the module path matches DEBUG, or if they
+    /// are nested in a group matching `group:<label>`
+    ///
+    /// It doesn't lock or allocate when DEBUG matches nothing or everything
+    #[doc(hidden)]
+    #[inline]
+    pub fn should_log(file: &str, line: u32, module: &str) -> bool {
+        match DEBUG_STATE.load(Ordering::Relaxed) {
+            STATE_OFF => false,
+            STATE_ALL => true,
+            _ => should_log_slow(file, line, module),
+        }
+    }
+
+    fn should_log_slow(file: &str, line: u32, module: &str) -> bool {
+        {
+            let debug = DEBUG.lock().unwrap();
+            // DEBUG is initialized from the env lazily
+            DEBUG_STATE.store(debug.state(), Ordering::Relaxed);
+            if debug.state() == STATE_OFF {
+                return false;
+            }
+            if debug.matches(file, line, module) {
+                return true;
+            }
+        }
+        with_levels(|levels| levels.last().is_some_and(|x| x.enabled))
+    }
+
+    /// Open a group if it should be logged. `label` is only evaluated when needed
+    #[doc(hidden)]
+    pub fn enter_group(
+        file: &str,
+        line: u32,
+        module: &str,
+        label: impl FnOnce() -> String,
+        timed: bool,
+    ) -> Option<GroupGuard> {
+        if DEBUG_STATE.load(Ordering::Relaxed) == STATE_OFF {
+            return None;
+        }
+
+        let label = if should_log(file, line, module) {
+            label()
+        } else if DEBUG.lock().unwrap().has_group_pattern() {
+            let label = label();
+            if !DEBUG.lock().unwrap().matches_group(&label) {
+                return None;
             }
+            label
+        } else {
+            return None;
         };
+
+        push_level(&label, timed);
+        Some(GroupGuard)
+    }
+
+    /// Open a group that is closed by [`group_end`] instead of a guard
+    #[doc(hidden)]
+    pub fn group_begin(file: &str, line: u32, module: &str, label: impl FnOnce() -> String) {
+        let guard = enter_group(file, line, module, label, false);
+        BEGUN.lock().unwrap().push(guard.is_some());
+        // The group is closed by `group_end!`
+        std::mem::forget(guard);
+    }
+
+    /// Close the last group opened by [`group_begin`]. Without one, it warns once and
+    /// does nothing
+    #[doc(hidden)]
+    pub fn group_end(file: &str, line: u32, label: impl FnOnce() -> Option<String>) {
+        match BEGUN.lock().unwrap().pop() {
+            Some(true) => {}
+            Some(false) => return,
+            None => {
+                static WARNED: AtomicBool = AtomicBool::new(false);
+                if DEBUG_STATE.load(Ordering::Relaxed) != STATE_OFF
+                    && !WARNED.swap(true, Ordering::Relaxed)
+                {
+                    log_message(
+                        &format!("{}:{}", file, line),
+                        "warning: group_end!() without a matching group_begin!()".to_string(),
+                    );
+                }
+                return;
+            }
+        }
+
+        match label() {
+            Some(label) => pop_level(Some((&label, &format!("{}:{}", file, line)))),
+            None => pop_level(None),
+        }
+    }
+
+    pub(crate) mod filter {
+        use super::normalize_path;
+
+        /// The parsed DEBUG value. An empty value, `0`, `false` and `off` match nothing,
+        /// while `1`, `true` and `all` match everything. Otherwise it's a comma separated
+        /// list of patterns:
+        ///
+        /// - `*` matches everything
+        /// - `path` matches the files whose paths contain it. `\\` and `/` are the same
+        /// - `path:12`, `path:200-400` and `path:200-` only match the given lines. More
+        ///   ranges of the same file can follow, e.g. `path:1-10,20-30`
+        /// - `mod:a::b` matches the module paths containing the segments `a::b`
+        /// - `group:label` matches everything inside the groups whose labels contain it
+        #[derive(Debug, Clone, Default, PartialEq, Eq)]
+        pub(crate) struct Filter {
+            pub patterns: Vec<Pattern>,
+        }
+
+        #[derive(Debug, Clone, PartialEq, Eq)]
+        pub(crate) enum Pattern {
+            All,
+            /// Inclusive line ranges. Empty means all the lines
+            File {
+                path: String,
+                lines: Vec<(u32, u32)>,
+            },
+            Module(Vec<String>),
+            Group(String),
+        }
+
+        impl Filter {
+            pub fn parse(s: &str) -> Self {
+                let s = s.trim();
+                if ["", "0", "false", "off"]
+                    .iter()
+                    .any(|x| s.eq_ignore_ascii_case(x))
+                {
+                    return Filter::default();
+                }
+                if ["1", "true", "all"]
+                    .iter()
+                    .any(|x| s.eq_ignore_ascii_case(x))
+                {
+                    return Filter {
+                        patterns: vec![Pattern::All],
+                    };
+                }
+
+                let mut patterns = Vec::new();
+                for x in s.split(',').map(str::trim).filter(|x| !x.is_empty()) {
+                    // A range after a file with ranges is one more range of it. Otherwise
+                    // it's a path
+                    let ranged = matches!(
+                        patterns.last(),
+                        Some(Pattern::File { lines, .. }) if !lines.is_empty()
+                    );
+                    if x == "*" {
+                        patterns.push(Pattern::All);
+                    } else if let Some(label) = x.strip_prefix("group:") {
+                        patterns.push(Pattern::Group(label.to_string()));
+                    } else if let Some(module) = x.strip_prefix("mod:") {
+                        patterns.push(Pattern::Module(
+                            module.split("::").map(|x| x.to_string()).collect(),
+                        ));
+                    } else if let Some(range) = parse_range(x).filter(|_| ranged) {
+                        if let Some(Pattern::File { lines, .. }) = patterns.last_mut() {
+                            lines.push(range);
+                        }
+                    } else if let Some((path, range)) = x
+                        .rsplit_once(':')
+                        .and_then(|(path, range)| Some((path, parse_range(range)?)))
+                    {
+                        patterns.push(Pattern::File {
+                            path: normalize_path(path).into_owned(),
+                            lines: vec![range],
+                        });
+                    } else {
+                        patterns.push(Pattern::File {
+                            path: normalize_path(x).into_owned(),
+                            lines: Vec::new(),
+                        });
+                    }
+                }
+
+                Filter { patterns }
+            }
+
+            pub fn matches(&self, file: &str, line: u32, module: &str) -> bool {
+                let file = normalize_path(file);
+                self.patterns.iter().any(|x| match x {
+                    Pattern::All => true,
+                    Pattern::File { path, lines } => {
+                        file.contains(path.as_str())
+                            && (lines.is_empty()
+                                || lines.iter().any(|&(a, b)| a <= line && line <= b))
+                    }
+                    // The segments must appear in a row, so `mod:state` matches
+                    // `my_crate::state` but not `my_crate::statement`
+                    Pattern::Module(segments) => {
+                        let module: Vec<&str> = module.split("::").collect();
+                        module
+                            .windows(segments.len())
+                            .any(|w| w == segments.as_slice())
+                    }
+                    Pattern::Group(_) => false,
+                })
+            }
+
+            pub fn matches_group(&self, label: &str) -> bool {
+                self.patterns
+                    .iter()
+                    .any(|x| matches!(x, Pattern::Group(x) if label.contains(x.as_str())))
+            }
+
+            /// See [`super::DEBUG_STATE`]
+            pub fn state(&self) -> u8 {
+                if self.patterns.is_empty() {
+                    super::STATE_OFF
+                } else if self.patterns.contains(&Pattern::All) {
+                    super::STATE_ALL
+                } else {
+                    super::STATE_CHECK
+                }
+            }
+
+            pub fn has_group_pattern(&self) -> bool {
+                self.patterns.iter().any(|x| matches!(x, Pattern::Group(_)))
+            }
+        }
+
+        /// `12`, `200-400` or `200-`
+        fn parse_range(s: &str) -> Option<(u32, u32)> {
+            match s.split_once('-') {
+                None => s.parse().ok().map(|x| (x, x)),
+                Some((a, "")) => Some((a.parse().ok()?, u32::MAX)),
+                Some((a, b)) => Some((a.parse().ok()?, b.parse().ok()?)),
+            }
+        }
+    }
+
+    /// Group the following logs until the guard is dropped
+    ///
+    /// Without arguments, the group is labeled with the enclosing function and `file:line`
+    #[macro_export]
+    macro_rules! group {
         () => {
-            let line = format!("{}:{}", file!(), line!());
-            if $crate::should_log(&line) {
-                let space = format!("{}", "    ".repeat($crate::get_level()));
-                $crate::inner_println!("{}[{}] ",space, line);
+            let __debug_log_group_guard = $crate::enter_group(
+                file!(),
+                line!(),
+                module_path!(),
+                || $crate::default_group_label!(format!("{}:{}", file!(), line!())),
+                false,
+            );
+        };
+        ($($arg:tt)*) => {
+            let __debug_log_group_guard =
+                $crate::enter_group(file!(), line!(), module_path!(), || format!($($arg)*), false);
+        };
+    }
+
+    #[doc(hidden)]
+    #[macro_export]
+    macro_rules! default_group_label {
+        ($line:expr) => {{
+            fn f() {}
+            fn type_name_of<T>(_: T) -> &'static str {
+                std::any::type_name::<T>()
             }
+            $crate::group_label(type_name_of(f), &$line)
+        }};
+    }
+
+    /// `type_name` is the name of a function defined inside the caller
+    #[doc(hidden)]
+    pub fn group_label(type_name: &str, line: &str) -> String {
+        let mut name = type_name.strip_suffix("::f").unwrap_or(type_name);
+        while let Some(outer) = name.strip_suffix("::{{closure}}") {
+            name = outer;
         }
+        format!("{} {}", name, line)
     }
 
-    /// Use it like println!(). Except it can be filtered by DEBUG env and can only log on debug mode
+    /// The body of a function with `#[group_fn]`
+    #[doc(hidden)]
     #[macro_export]
-    macro_rules! debug_log {
-        ($($arg:tt)*) => {{
-            let line = format!("{}:{}", file!(), line!());
-            if $crate::should_log(&line) {
-                let prefix = format!("{}[{}] ", "    ".repeat($crate::get_level()), line);
-                let s = format!($($arg)*);
-                $crate::inner_println!("{}{}", prefix, $crate::prepend_indent(s));
+    macro_rules! __group_fn {
+        ([$($arg:ident),*], $body:block) => {{
+            $crate::group!();
+            $crate::__group_fn!(@args $($arg),*);
+            $body
+        }};
+        ([$($arg:ident),*], ret $ty:ty, $body:expr) => {{
+            $crate::group!();
+            $crate::__group_fn!(@args $($arg),*);
+            let ret: $ty = $body;
+            if $crate::should_log(file!(), line!(), module_path!()) {
+                let line = format!("{}:{}", file!(), line!());
+                $crate::dbg(&ret, "return", &line);
             }
+            ret
         }};
-        () => {
-            if $crate::should_log(&file!()) {
-                $crate::inner_println();
+        (@args) => {};
+        (@args $($arg:ident),+) => {
+            if $crate::should_log(file!(), line!(), module_path!()) {
+                let line = format!("{}:{}", file!(), line!());
+                $($crate::dbg(&$arg, stringify!($arg), &line);)+
             }
         };
     }
-}
-
-#[cfg(not(debug_assertions))]
-mod debug {
-    pub fn set_debug(s: &str) {}
 
-    /// Group the following logs until the guard is dropped
+    /// Open a group that lasts until `group_end!`, so it can end in another scope or
+    /// function. It takes the same arguments as `group!`
     #[macro_export]
-    macro_rules! group {
-        ($($arg:tt)*) => {};
-        () => {};
+    macro_rules! group_begin {
+        () => {
+            $crate::group_begin(file!(), line!(), module_path!(), || {
+                $crate::default_group_label!(format!("{}:{}", file!(), line!()))
+            })
+        };
+        ($($arg:tt)*) => {
+            $crate::group_begin(file!(), line!(), module_path!(), || format!($($arg)*))
+        };
     }
 
-    /// Use it like println!(). Except it can be filtered by DEBUG env and can only log on debug mode
+    /// Close the group opened by the last `group_begin!`. With a label, it warns when the
+    /// label of the closed group is different
     #[macro_export]
-    macro_rules! debug_log {
-        ($($arg:tt)*) => {{}};
-        () => {};
+    macro_rules! group_end {
+        () => {
+            $crate::group_end(file!(), line!(), || None)
+        };
+        ($($arg:tt)*) => {
+            $crate::group_end(file!(), line!(), || Some(format!($($arg)*)))
+        };
     }
 
-    /// It's just dbg!() with indent and can be filtered by DEBUG env
+    /// Like `group!`, but the closing line shows how long the group took, e.g. `} // 12.4ms`
     #[macro_export]
-    macro_rules! debug_dbg {
-        ($($val:expr),+ $(,)?) => {};
-        () => {};
+    macro_rules! group_timed {
+        () => {
+            let __debug_log_group_guard = $crate::enter_group(
+                file!(),
+                line!(),
+                module_path!(),
+                || $crate::default_group_label!(format!("{}:{}", file!(), line!())),
+                true,
+            );
+        };
+        ($($arg:tt)*) => {
+            let __debug_log_group_guard =
+                $crate::enter_group(file!(), line!(), module_path!(), || format!($($arg)*), true);
+        };
     }
 
     #[doc(hidden)]
     pub struct GroupGuard;
-}
+    impl Drop for GroupGuard {
+        fn drop(&mut self) {
+            crate::outdent();
+        }
+    }
 
-pub use debug::*;
+    /// Run a future inside a group. The groups opened while polling it belong to the
+    /// future instead of being shared with the other futures polled on the same thread,
+    /// so interleaved tasks don't nest inside each other's groups
+    pub trait DebugGroupExt: Future + Sized {
+        #[track_caller]
+        fn debug_group(self, label: impl Into<String>) -> DebugGroup<Self> {
+            let location = std::panic::Location::caller();
+            DebugGroup {
+                fut: self,
+                label: label.into(),
+                file: location.file(),
+                line: location.line(),
+                levels: None,
+                opened: false,
+            }
+        }
+    }
 
-#[cfg(test)]
-mod tests {
-    use crate::{debug_dbg, debug_log, group};
+    impl<F: Future> DebugGroupExt for F {}
 
-    #[test]
-    /// Run this test with
-    /// DEBUG=* cargo test -- --nocapture &> data.log
-    fn it_works() {
-        group!("A Group");
-        group!("C Group");
-        {
-            group!("Sub A Group");
-            let arr: Vec<_> = (0..3).collect();
-            debug_dbg!(&arr);
-            {
-                group!("Sub Sub A Group");
-                debug_dbg!(&arr);
+    /// The future returned by [`DebugGroupExt::debug_group`]
+    pub struct DebugGroup<F> {
+        fut: F,
+        label: String,
+        file: &'static str,
+        line: u32,
+        /// The levels of the future while it's not polled. `None` before the first poll
+        levels: Option<Vec<Level>>,
+        /// Whether its group is open
+        opened: bool,
+    }
+
+    impl<F> DebugGroup<F> {
+        /// Make the levels of the future the ones of this thread while `f` runs. The
+        /// other threads keep theirs, as the future may be polled on any of them
+        fn with_task_levels<R>(&mut self, f: impl FnOnce(&mut Self) -> R) -> R {
+            struct Restore<'a, F> {
+                group: &'a mut DebugGroup<F>,
+                outer: Option<Vec<Level>>,
             }
-            debug_log!("Hi");
-            debug_dbg!(&arr);
+
+            impl<F> Drop for Restore<'_, F> {
+                fn drop(&mut self) {
+                    let outer = self.outer.take();
+                    let _ = TASK_LEVELS.try_with(|task| {
+                        self.group.levels = std::mem::replace(&mut *task.borrow_mut(), outer);
+                    });
+                }
+            }
+
+            // It starts inside the groups of whoever polls it first
+            let inner = match self.levels.take() {
+                Some(levels) => levels,
+                None => with_levels(|levels| levels.clone()),
+            };
+            match TASK_LEVELS.try_with(|task| task.borrow_mut().replace(inner)) {
+                Ok(outer) => {
+                    let restore = Restore { group: self, outer };
+                    f(&mut *restore.group)
+                }
+                Err(_) => f(self),
+            }
+        }
+    }
+
+    impl<F: Future> Future for DebugGroup<F> {
+        type Output = F::Output;
+
+        fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<F::Output> {
+            // SAFETY: `fut` is never moved
+            let this = unsafe { self.get_unchecked_mut() };
+            let first = this.levels.is_none();
+            this.with_task_levels(|this| {
+                if first {
+                    let label = std::mem::take(&mut this.label);
+                    let guard = enter_group(this.file, this.line, "", || label, false);
+                    this.opened = guard.is_some();
+                    // The group is closed when the future is done
+                    std::mem::forget(guard);
+                }
+                // SAFETY: `fut` is never moved
+                let ans = unsafe { Pin::new_unchecked(&mut this.fut) }.poll(cx);
+                if ans.is_ready() && this.opened {
+                    this.opened = false;
+                    outdent();
+                }
+                ans
+            })
         }
+    }
 
-        {
-            group!("B Group");
-            debug_log!("END");
+    impl<F> Drop for DebugGroup<F> {
+        fn drop(&mut self) {
+            if self.opened {
+                self.with_task_levels(|this| {
+                    this.opened = false;
+                    outdent();
+                });
+            }
+        }
+    }
+
+    /// Run `f` inside a group and return its value. The group is closed even if `f` panics
+    #[track_caller]
+    pub fn group_scope<R>(name: &str, f: impl FnOnce() -> R) -> R {
+        let location = std::panic::Location::caller();
+        // The module path of the caller is unknown, so `mod:` patterns can't match here
+        let _guard = enter_group(
+            location.file(),
+            location.line(),
+            "",
+            || name.to_string(),
+            false,
+        );
+        f()
+    }
+
+    #[doc(hidden)]
+    pub fn hex<T: AsRef<[u8]> + ?Sized>(bytes: &T, name: &str, line: &str, max: Option<usize>) {
+        let bytes = bytes.as_ref();
+        let max = max.unwrap_or_else(|| MAX_VALUE_SIZE.lock().unwrap().0.saturating_mul(16));
+        log_message(line, format_hex(bytes, name, max));
+    }
+
+    /// A classic hexdump of at most `max` bytes, 16 bytes per row
+    fn format_hex(bytes: &[u8], name: &str, max: usize) -> String {
+        let mut ans = format!("{} = {} bytes", name, bytes.len());
+        let shown = &bytes[..bytes.len().min(max)];
+        for (row, chunk) in shown.chunks(16).enumerate() {
+            ans.push_str(&format!("\n{:08x}  ", row * 16));
+            for i in 0..16 {
+                if i == 8 {
+                    ans.push(' ');
+                }
+                match chunk.get(i) {
+                    Some(b) => ans.push_str(&format!("{:02x} ", b)),
+                    None => ans.push_str("   "),
+                }
+            }
+            ans.push_str(" |");
+            ans.extend(chunk.iter().map(|&b| {
+                if b.is_ascii_graphic() || b == b' ' {
+                    b as char
+                } else {
+                    '.'
+                }
+            }));
+            ans.push('|');
+        }
+
+        if shown.len() < bytes.len() {
+            ans.push_str(&format!(
+                "\n... ({} more bytes truncated)",
+                bytes.len() - shown.len()
+            ));
+        }
+        ans
+    }
+
+    #[doc(hidden)]
+    pub fn table(name: &str, line: &str, headers: &[&str], rows: Vec<Vec<String>>) {
+        let max_width = *MAX_CELL_WIDTH.lock().unwrap();
+        log_message(line, format_table(name, headers, rows, max_width));
+    }
+
+    fn format_table(
+        name: &str,
+        headers: &[&str],
+        rows: Vec<Vec<String>>,
+        max_width: usize,
+    ) -> String {
+        let cut = |s: &str| -> String {
+            if s.chars().count() <= max_width {
+                s.to_string()
+            } else {
+                let mut s: String = s.chars().take(max_width.saturating_sub(1)).collect();
+                s.push('…');
+                s
+            }
+        };
+        let headers: Vec<String> = headers.iter().map(|x| cut(x)).collect();
+        let rows: Vec<Vec<String>> = rows
+            .iter()
+            .map(|row| row.iter().map(|x| cut(x)).collect())
+            .collect();
+        let widths: Vec<usize> = (0..headers.len())
+            .map(|i| {
+                rows.iter()
+                    .map(|row| row[i].chars().count())
+                    .chain(std::iter::once(headers[i].chars().count()))
+                    .max()
+                    .unwrap()
+            })
+            .collect();
+
+        let format_row = |row: &[String]| {
+            let mut ans = String::from("|");
+            for (cell, width) in row.iter().zip(widths.iter()) {
+                ans.push(' ');
+                ans.push_str(cell);
+                ans.push_str(&" ".repeat(width - cell.chars().count()));
+                ans.push_str(" |");
+            }
+            ans
+        };
+
+        let mut ans = format!("{} = {} rows", name, rows.len());
+        ans.push('\n');
+        ans.push_str(&format_row(&headers));
+        ans.push_str("\n|");
+        for width in widths.iter() {
+            ans.push_str(&"-".repeat(width + 2));
+            ans.push('|');
+        }
+        for row in rows.iter() {
+            ans.push('\n');
+            ans.push_str(&format_row(row));
         }
+        ans
+    }
+
+    #[doc(hidden)]
+    pub fn count(label: &str, line: &str) {
+        let n = {
+            let mut counts = COUNTS.lock().unwrap();
+            let n = counts.entry(label.to_string()).or_default();
+            *n += 1;
+            *n
+        };
+        log_message(line, format!("{}: {}", label, n));
+    }
+
+    #[doc(hidden)]
+    pub fn count_reset(label: &str) {
+        COUNTS.lock().unwrap().remove(label);
+    }
+
+    /// Log a hexdump of anything `AsRef<[u8]>`. Limit the number of bytes shown with
+    /// `debug_hex!(&bytes, max = 256)`, otherwise it's limited by `DEBUG_MAX_LINES` rows
+    ///
+    /// Like `debug_dbg!`, the expression is always evaluated
+    #[macro_export]
+    macro_rules! debug_hex {
+        ($val:expr $(, max = $max:expr)? $(,)?) => {
+            match $val {
+                tmp => {
+                    if $crate::should_log(file!(), line!(), module_path!()) {
+                        let line = format!("{}:{}", file!(), line!());
+                        let max: Option<usize> = None $(.or(Some($max)))?;
+                        $crate::hex(&tmp, stringify!($val), &line, max);
+                    }
+                }
+            }
+        };
+    }
+
+    /// Like `console.table()`. Log the items as an aligned table, with a column for each
+    /// expression
+    ///
+    /// ```rust
+    /// # use debug_log::debug_table;
+    /// let spans = vec![(1, "a"), (2, "bc")];
+    /// debug_table!(&spans, [s => s.0, s => s.1, s => format!("{:?}", s.1)]);
+    /// ```
+    ///
+    /// The iterator is only evaluated when the table is logged
+    #[macro_export]
+    macro_rules! debug_table {
+        ($iter:expr, [$($var:ident => $col:expr),+ $(,)?] $(,)?) => {{
+            if $crate::should_log(file!(), line!(), module_path!()) {
+                let line = format!("{}:{}", file!(), line!());
+                let rows: Vec<Vec<String>> = ::std::iter::IntoIterator::into_iter($iter)
+                    .map(|item| vec![$({
+                        let $var = &item;
+                        ($col).to_string()
+                    }),+])
+                    .collect();
+                $crate::table(stringify!($iter), &line, &[$(stringify!($col)),+], rows);
+            }
+        }};
+    }
+
+    /// Like `console.count()`. Count how many times it's called with the label, and
+    /// log the count. Filtered out calls are not counted
+    #[macro_export]
+    macro_rules! debug_count {
+        () => {
+            $crate::debug_count!("default")
+        };
+        ($label:expr) => {{
+            if $crate::should_log(file!(), line!(), module_path!()) {
+                let line = format!("{}:{}", file!(), line!());
+                $crate::count(&$label, &line);
+            }
+        }};
+    }
+
+    /// Reset the count of the label used by `debug_count!`
+    #[macro_export]
+    macro_rules! debug_count_reset {
+        () => {
+            $crate::debug_count_reset!("default")
+        };
+        ($label:expr) => {
+            $crate::count_reset(&$label)
+        };
+    }
+
+    /// It can be filtered by DEBUG env and can only log on debug mode.
+    ///
+    /// Like `dbg!()`, it returns the value of the expression, or a tuple of the values
+    /// when given multiple expressions
+    #[macro_export]
+    macro_rules! debug_dbg {
+        () => {{
+            if $crate::should_log(file!(), line!(), module_path!()) {
+                let line = format!("{}:{}", file!(), line!());
+                $crate::log_message(&line, String::new());
+            }
+        }};
+        ($val:expr $(,)?) => {
+            match $val {
+                tmp => {
+                    if $crate::should_log(file!(), line!(), module_path!()) {
+                        let line = format!("{}:{}", file!(), line!());
+                        $crate::dbg(&tmp, stringify!($val), &line);
+                    }
+                    tmp
+                }
+            }
+        };
+        ($($val:expr),+ $(,)?) => {
+            ($($crate::debug_dbg!($val)),+,)
+        };
+    }
+
+    /// Like `debug_dbg!`, but each value is printed on one line with `{:?}`, unless the
+    /// line would be wider than [`set_max_line_width`]
+    #[macro_export]
+    macro_rules! debug_dbg_compact {
+        () => {
+            $crate::debug_dbg!()
+        };
+        ($val:expr $(,)?) => {
+            match $val {
+                tmp => {
+                    if $crate::should_log(file!(), line!(), module_path!()) {
+                        let line = format!("{}:{}", file!(), line!());
+                        $crate::dbg_compact(&tmp, stringify!($val), &line);
+                    }
+                    tmp
+                }
+            }
+        };
+        ($($val:expr),+ $(,)?) => {
+            ($($crate::debug_dbg_compact!($val)),+,)
+        };
+    }
+
+    /// Use it like println!(). Except it can be filtered by DEBUG env and can only log on debug mode
+    #[macro_export]
+    macro_rules! debug_log {
+        () => {{
+            if $crate::should_log(file!(), line!(), module_path!()) {
+                let line = format!("{}:{}", file!(), line!());
+                $crate::log_message(&line, String::new());
+            }
+        }};
+        ($($arg:tt)*) => {{
+            if $crate::should_log(file!(), line!(), module_path!()) {
+                let line = format!("{}:{}", file!(), line!());
+                $crate::log_message(&line, format!($($arg)*));
+            }
+        }};
+    }
+
+    #[doc(hidden)]
+    pub fn log_message(line: &str, s: String) {
+        emit(&render(Event::Log { line, msg: &s }, get_level()));
+    }
+
+    /// Like `debug_log!`, but only logs when the condition is true. The condition is
+    /// not evaluated when the call site is filtered out
+    #[macro_export]
+    macro_rules! debug_log_if {
+        ($cond:expr) => {{
+            if $crate::should_log(file!(), line!(), module_path!()) && $cond {
+                let line = format!("{}:{}", file!(), line!());
+                $crate::log_message(&line, String::new());
+            }
+        }};
+        ($cond:expr, $($arg:tt)*) => {{
+            if $crate::should_log(file!(), line!(), module_path!()) && $cond {
+                let line = format!("{}:{}", file!(), line!());
+                $crate::log_message(&line, format!($($arg)*));
+            }
+        }};
+    }
+
+    /// Like `debug_log!`, but only logs the first time this call site is reached
+    #[macro_export]
+    macro_rules! debug_log_once {
+        ($($arg:tt)*) => {{
+            static DONE: ::std::sync::atomic::AtomicBool = ::std::sync::atomic::AtomicBool::new(false);
+            if $crate::should_log(file!(), line!(), module_path!())
+                && !DONE.swap(true, ::std::sync::atomic::Ordering::Relaxed)
+            {
+                $crate::debug_log!($($arg)*);
+            }
+        }};
+    }
+
+    /// Like `debug_log!`, but only logs every `n`-th time this call site is reached,
+    /// noting how many were skipped, e.g. `[src/lib.rs:12] Hi (skipped 99)`
+    #[macro_export]
+    macro_rules! debug_log_every {
+        ($n:expr, $($arg:tt)*) => {{
+            static COUNT: ::std::sync::atomic::AtomicUsize = ::std::sync::atomic::AtomicUsize::new(0);
+            if $crate::should_log(file!(), line!(), module_path!()) {
+                let n: usize = $n;
+                let count = COUNT.fetch_add(1, ::std::sync::atomic::Ordering::Relaxed);
+                if count % n.max(1) == 0 {
+                    let line = format!("{}:{}", file!(), line!());
+                    let mut s = format!($($arg)*);
+                    if count > 0 && n > 1 {
+                        s.push_str(&format!(" (skipped {})", n - 1));
+                    }
+                    $crate::log_message(&line, s);
+                }
+            }
+        }};
+    }
+
+    /// Like `debug_dbg!`, but only logs when the condition is true. Neither the
+    /// condition nor the expressions are evaluated when the call site is filtered out
+    #[macro_export]
+    macro_rules! debug_dbg_if {
+        ($cond:expr, $($val:expr),+ $(,)?) => {{
+            if $crate::should_log(file!(), line!(), module_path!()) && $cond {
+                let line = format!("{}:{}", file!(), line!());
+                $($crate::dbg(&$val, stringify!($val), &line);)+
+            }
+        }};
+    }
+}
+
+#[cfg(not(debug_assertions))]
+mod debug {
+    use std::future::Future;
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+
+    use crate::{Backend, ColorMode, Format, Target, TimeMode, WasmGroupMode};
+
+    pub fn set_debug(_s: &str) {}
+
+    /// Override the DEBUG value until the returned guard is dropped
+    pub fn with_debug(_s: &str) -> DebugGuard {
+        DebugGuard
+    }
+
+    #[must_use = "the previous DEBUG value is restored when the guard is dropped"]
+    pub struct DebugGuard;
+
+    // So `drop(guard)` reads the same in both modes
+    impl Drop for DebugGuard {
+        fn drop(&mut self) {}
+    }
+
+    pub fn set_wasm_group_mode(_mode: WasmGroupMode) {}
+
+    pub fn set_backend(_backend: Backend) {}
+
+    pub fn set_target(_target: Target) {}
+
+    pub fn set_format(_format: Format) {}
+
+    pub fn set_time_mode(_mode: TimeMode) {}
+
+    pub fn set_close_labels(_enabled: bool) {}
+
+    pub fn set_dedup(_enabled: bool) {}
+
+    pub fn set_color(_mode: ColorMode) {}
+
+    pub fn set_max_value_size(_lines: usize, _chars: usize) {}
+
+    pub fn set_max_cell_width(_width: usize) {}
+
+    pub fn set_max_line_width(_width: usize) {}
+
+    pub fn set_indent(_indent: &str) {}
+
+    #[doc(hidden)]
+    pub type Sink = Box<dyn FnMut(&str) + Send>;
+
+    #[doc(hidden)]
+    pub fn set_sink(sink: Option<Sink>) -> Option<Sink> {
+        sink
+    }
+
+    pub fn set_log_file(_path: Option<std::path::PathBuf>) {}
+
+    pub fn enable_panic_dump(_capacity: usize) {}
+
+    /// Run `f` and return everything it logged, which is nothing in release mode
+    pub fn capture(f: impl FnOnce()) -> String {
+        f();
+        String::new()
+    }
+
+    /// Group the following logs until the guard is dropped
+    #[macro_export]
+    macro_rules! group {
+        () => {};
+        ($($arg:tt)*) => {};
+    }
+
+    /// Like `group!`, but the closing line shows how long the group took, e.g. `} // 12.4ms`
+    #[macro_export]
+    macro_rules! group_timed {
+        ($($arg:tt)*) => {};
+    }
+
+    /// Open a group that lasts until `group_end!`
+    #[macro_export]
+    macro_rules! group_begin {
+        ($($arg:tt)*) => {};
+    }
+
+    /// Close the group opened by the last `group_begin!`
+    #[macro_export]
+    macro_rules! group_end {
+        ($($arg:tt)*) => {};
+    }
+
+    #[doc(hidden)]
+    #[macro_export]
+    macro_rules! __group_fn {
+        ([$($arg:ident),*], $body:block) => {
+            $body
+        };
+        ([$($arg:ident),*], ret $ty:ty, $body:expr) => {
+            $body
+        };
+    }
+
+    /// Use it like println!(). Except it can be filtered by DEBUG env and can only log on debug mode
+    #[macro_export]
+    macro_rules! debug_log {
+        () => {{}};
+        ($($arg:tt)*) => {{}};
+    }
+
+    /// Like `console.count()`. Count how many times it's called with the label, and
+    /// log the count. Filtered out calls are not counted
+    #[macro_export]
+    macro_rules! debug_count {
+        ($($label:expr)?) => {};
+    }
+
+    /// Like `console.table()`. Log the items as an aligned table, with a column for each
+    /// expression
+    #[macro_export]
+    macro_rules! debug_table {
+        ($iter:expr, [$($var:ident => $col:expr),+ $(,)?] $(,)?) => {};
+    }
+
+    /// Log a hexdump of anything `AsRef<[u8]>`.
+    ///
+    /// Like `debug_dbg!`, the expression is still evaluated in release mode
+    #[macro_export]
+    macro_rules! debug_hex {
+        ($val:expr $(, max = $max:expr)? $(,)?) => {{
+            let _ = $val;
+        }};
+    }
+
+    /// Reset the count of the label used by `debug_count!`
+    #[macro_export]
+    macro_rules! debug_count_reset {
+        ($($label:expr)?) => {};
+    }
+
+    /// Like `debug_log!`, but only logs when the condition is true
+    #[macro_export]
+    macro_rules! debug_log_if {
+        ($cond:expr $(, $($arg:tt)*)?) => {};
+    }
+
+    /// Like `debug_log!`, but only logs the first time this call site is reached
+    #[macro_export]
+    macro_rules! debug_log_once {
+        ($($arg:tt)*) => {};
+    }
+
+    /// Like `debug_log!`, but only logs every `n`-th time this call site is reached
+    #[macro_export]
+    macro_rules! debug_log_every {
+        ($n:expr, $($arg:tt)*) => {};
+    }
+
+    /// Like `debug_dbg!`, but only logs when the condition is true
+    #[macro_export]
+    macro_rules! debug_dbg_if {
+        ($cond:expr, $($val:expr),+ $(,)?) => {};
+    }
+
+    /// It's just dbg!() with indent and can be filtered by DEBUG env.
+    ///
+    /// The expressions are still evaluated and returned in release mode
+    #[macro_export]
+    macro_rules! debug_dbg {
+        () => {
+            ()
+        };
+        ($val:expr $(,)?) => {
+            match $val {
+                tmp => tmp,
+            }
+        };
+        ($($val:expr),+ $(,)?) => {
+            ($($crate::debug_dbg!($val)),+,)
+        };
+    }
+
+    /// Like `debug_dbg!`, but each value is printed on one line unless it's too wide.
+    ///
+    /// The expressions are still evaluated and returned in release mode
+    #[macro_export]
+    macro_rules! debug_dbg_compact {
+        ($($val:expr),* $(,)?) => {
+            $crate::debug_dbg!($($val),*)
+        };
+    }
+
+    #[doc(hidden)]
+    pub struct GroupGuard;
+
+    #[doc(hidden)]
+    pub fn get_level() -> usize {
+        0
+    }
+
+    #[doc(hidden)]
+    pub fn should_log(_file: &str, _line: u32, _module: &str) -> bool {
+        false
+    }
+
+    /// Run `f` inside a group and return its value. The group is closed even if `f` panics
+    #[inline(always)]
+    pub fn group_scope<R>(_name: &str, f: impl FnOnce() -> R) -> R {
+        f()
+    }
+
+    /// Run a future inside a group
+    pub trait DebugGroupExt: Future + Sized {
+        #[inline(always)]
+        fn debug_group(self, _label: impl Into<String>) -> DebugGroup<Self> {
+            DebugGroup { fut: self }
+        }
+    }
+
+    impl<F: Future> DebugGroupExt for F {}
+
+    /// The future returned by [`DebugGroupExt::debug_group`]
+    pub struct DebugGroup<F> {
+        fut: F,
+    }
+
+    impl<F: Future> Future for DebugGroup<F> {
+        type Output = F::Output;
+
+        #[inline(always)]
+        fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<F::Output> {
+            // SAFETY: `fut` is never moved
+            unsafe { self.map_unchecked_mut(|x| &mut x.fut) }.poll(cx)
+        }
+    }
+}
+
+pub use debug::*;
+#[cfg(feature = "macros")]
+pub use debug_log_macros::group_fn;
+
+// Lets the code generated by `#[group_fn]` refer to `::debug_log` inside this crate
+#[cfg(feature = "macros")]
+extern crate self as debug_log;
+
+/// `#[group_fn(ret)]` turns `x?` into a `break` with these, as `Try` is unstable
+#[cfg(feature = "macros")]
+#[doc(hidden)]
+pub mod __private {
+    use std::convert::Infallible;
+
+    pub trait Branch {
+        type Output;
+        type Residual;
+
+        fn branch(self) -> Result<Self::Output, Self::Residual>;
+    }
+
+    impl<T, E> Branch for Result<T, E> {
+        type Output = T;
+        type Residual = Result<Infallible, E>;
+
+        #[inline(always)]
+        fn branch(self) -> Result<T, Self::Residual> {
+            self.map_err(Err)
+        }
+    }
+
+    impl<T> Branch for Option<T> {
+        type Output = T;
+        type Residual = Option<Infallible>;
+
+        #[inline(always)]
+        fn branch(self) -> Result<T, Self::Residual> {
+            self.ok_or(None)
+        }
+    }
+
+    pub trait FromResidual<R> {
+        fn from_residual(residual: R) -> Self;
+    }
+
+    impl<T, E, F: From<E>> FromResidual<Result<Infallible, E>> for Result<T, F> {
+        #[inline(always)]
+        fn from_residual(residual: Result<Infallible, E>) -> Self {
+            match residual {
+                Ok(x) => match x {},
+                Err(err) => Err(From::from(err)),
+            }
+        }
+    }
+
+    impl<T> FromResidual<Option<Infallible>> for Option<T> {
+        #[inline(always)]
+        fn from_residual(_: Option<Infallible>) -> Self {
+            None
+        }
+    }
+}
+
+/// The output is only checked in debug mode. See `release_tests` for release mode
+#[cfg(all(test, debug_assertions))]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use crate::{debug_dbg, debug_dbg_compact, debug_log, group, group_begin, group_end};
+
+    /// Tests touching the global DEBUG value or the sink must hold this lock
+    static TEST_LOCK: Mutex<()> = Mutex::new(());
+
+    fn lock_test() -> std::sync::MutexGuard<'static, ()> {
+        TEST_LOCK.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Run `f` with `DEBUG=*` and return the entries it emitted
+    fn collect(f: impl FnOnce()) -> Vec<String> {
+        let entries = Arc::new(Mutex::new(Vec::new()));
+        let cloned = entries.clone();
+        let _debug = crate::with_debug("*");
+        let prev = crate::set_sink(Some(Box::new(move |s: &str| {
+            cloned.lock().unwrap().push(s.to_string())
+        })));
+        f();
+        crate::set_sink(prev);
+        let ans = entries.lock().unwrap().clone();
+        ans
+    }
+
+    /// Remove the line numbers from the `file:line` locations
+    fn strip_lines(s: &str) -> String {
+        regex::Regex::new(r"(src/lib\.rs):\d+")
+            .unwrap()
+            .replace_all(s, "$1")
+            .into_owned()
+    }
+
+    /// Counts the allocations of the current thread
+    struct CountingAlloc;
+
+    thread_local! {
+        static ALLOCATIONS: std::cell::Cell<usize> = const { std::cell::Cell::new(0) };
+    }
+
+    unsafe impl std::alloc::GlobalAlloc for CountingAlloc {
+        unsafe fn alloc(&self, layout: std::alloc::Layout) -> *mut u8 {
+            let _ = ALLOCATIONS.try_with(|x| x.set(x.get() + 1));
+            std::alloc::System.alloc(layout)
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: std::alloc::Layout) {
+            std::alloc::System.dealloc(ptr, layout)
+        }
+    }
+
+    #[global_allocator]
+    static ALLOCATOR: CountingAlloc = CountingAlloc;
+
+    #[test]
+    /// Run this test with
+    /// DEBUG=* cargo test -- --nocapture &> data.log
+    fn it_works() {
+        let _lock = lock_test();
+        group!("A Group");
+        group!("C Group");
+        {
+            group!("Sub A Group");
+            let arr: Vec<_> = (0..3).collect();
+            debug_dbg!(&arr);
+            {
+                group!("Sub Sub A Group");
+                debug_dbg!(&arr);
+            }
+            debug_log!("Hi");
+            debug_dbg!(&arr);
+        }
+
+        {
+            group!("B Group");
+            debug_log!("END");
+        }
+    }
+
+    #[test]
+    fn time_prefix() {
+        let _lock = lock_test();
+        let entries = collect(|| {
+            crate::set_time_mode(crate::TimeMode::Abs);
+            group!("A Group");
+            debug_dbg!(&[1, 2]);
+            crate::set_time_mode(crate::TimeMode::Elapsed);
+            debug_log!("Hi");
+            crate::set_time_mode(crate::TimeMode::Off);
+        });
+
+        let abs = regex::Regex::new(r"^\d{2}:\d{2}:\d{2}\.\d{3} ").unwrap();
+        let elapsed = regex::Regex::new(r"^ *\d+\.\d{3}ms ").unwrap();
+        assert!(abs.is_match(&entries[0]));
+        assert!(entries[0].ends_with(" A Group {"));
+        assert!(abs.is_match(&entries[1]));
+        let lines: Vec<_> = entries[1].lines().collect();
+        assert!(lines[0].ends_with("] &[1, 2] = ["));
+        assert_eq!(lines[1], format!("{}        1,", " ".repeat(13)));
+        assert_eq!(lines[3], format!("{}    ]", " ".repeat(13)));
+        assert!(elapsed.is_match(&entries[2]));
+        assert!(entries[2].contains("    [src/lib.rs:"));
+        assert_eq!(entries[3], "}");
+    }
+
+    #[test]
+    fn colors() {
+        let _lock = lock_test();
+        let log = || {
+            group!("A Group");
+            debug_dbg!(&[1]);
+            debug_log!("Hi");
+        };
+        crate::set_color(crate::ColorMode::Always);
+        let colored = collect(log);
+        crate::set_color(crate::ColorMode::Never);
+        let plain = collect(log);
+        crate::set_color(crate::ColorMode::Auto);
+
+        assert_eq!(colored[0], "\x1b[1;34mA Group\x1b[0m {");
+        assert_eq!(plain[0], "A Group {");
+        assert!(colored[1].starts_with("    [\x1b[90msrc/lib.rs:"));
+        assert!(colored[2].starts_with("    [\x1b[90msrc/lib.rs:"));
+        assert!(plain.iter().all(|s| !s.contains('\x1b')));
+        let strip = regex::Regex::new("\x1b\\[[0-9;]*m").unwrap();
+        for (colored, plain) in colored.iter().zip(plain.iter()) {
+            assert_eq!(strip.replace_all(colored, ""), *plain);
+        }
+    }
+
+    #[test]
+    fn group_scope() {
+        let _lock = lock_test();
+        let entries = collect(|| {
+            group!("A Group");
+            let ans = crate::group_scope("Scope", || {
+                debug_log!("Hi");
+                1 + 1
+            });
+            assert_eq!(ans, 2);
+        });
+        assert_eq!(entries.len(), 5);
+        assert_eq!(entries[1], "    Scope {");
+        assert_eq!(entries[3], "    }");
+        assert_eq!(entries[4], "}");
+    }
+
+    #[cfg(feature = "macros")]
+    #[test]
+    fn group_fn() {
+        use crate::group_fn;
+
+        #[group_fn(args, ret)]
+        fn factorial(n: u64) -> u64 {
+            if n <= 1 {
+                return 1;
+            }
+            n * factorial(n - 1)
+        }
+
+        struct NotDebug;
+
+        struct Counter(u32);
+
+        impl Counter {
+            #[group_fn(ret)]
+            fn get(&self) -> &u32 {
+                &self.0
+            }
+
+            #[group_fn(args, skip(_unused))]
+            fn add<T: Into<u32> + std::fmt::Debug>(&mut self, n: T, _unused: NotDebug) {
+                self.0 += n.into();
+                debug_dbg!(self.0);
+            }
+
+            #[group_fn(ret)]
+            fn iter(&self) -> impl Iterator<Item = u32> {
+                0..self.0
+            }
+        }
+
+        struct Values(Vec<u32>);
+
+        impl Values {
+            #[group_fn(ret)]
+            fn first_mut(&mut self) -> &mut u32 {
+                &mut self.0[0]
+            }
+        }
+
+        #[group_fn(args, ret)]
+        fn pick(v: &mut Vec<u32>) -> &mut u32 {
+            if v.len() > 1 {
+                return &mut v[1];
+            }
+            &mut v[0]
+        }
+
+        #[group_fn(ret)]
+        fn parse(s: &str) -> Result<u32, Box<dyn std::error::Error>> {
+            let n: u32 = s.parse()?;
+            Ok(n * 2)
+        }
+
+        #[group_fn(ret)]
+        fn first(v: &[u32]) -> Option<u32> {
+            Some(*v.first()? + 1)
+        }
+
+        let _lock = lock_test();
+        let output = {
+            let _debug = crate::with_debug("*");
+            crate::capture(|| assert_eq!(factorial(3), 6))
+        };
+        let re = regex::Regex::new(r"src/lib\.rs:\d+").unwrap();
+        assert_eq!(
+            re.replace_all(&output, "file"),
+            "debug_log::tests::group_fn::factorial file {
+    [file] n = 3
+    debug_log::tests::group_fn::factorial file {
+        [file] n = 2
+        debug_log::tests::group_fn::factorial file {
+            [file] n = 1
+            [file] return = 1
+        }
+        [file] return = 2
+    }
+    [file] return = 6
+}
+"
+        );
+
+        let mut counter = Counter(1);
+        let output = {
+            let _debug = crate::with_debug("*");
+            crate::capture(|| {
+                counter.add(2u8, NotDebug);
+                assert_eq!(*counter.get(), 3);
+                assert_eq!(counter.iter().count(), 3);
+            })
+        };
+        let output = re.replace_all(&output, "file");
+        assert!(output.starts_with(
+            "debug_log::tests::group_fn::Counter::add file {
+    [file] n = 2
+    [file] self.0 = 3
+}
+debug_log::tests::group_fn::Counter::get file {
+    [file] return = 3
+}
+"
+        ));
+
+        let mut values = Values(vec![1, 2]);
+        let output = {
+            let _debug = crate::with_debug("*");
+            crate::capture(|| {
+                *values.first_mut() += 10;
+                *pick(&mut values.0) += 20;
+                assert!(parse("x").is_err());
+                assert_eq!(parse("2").unwrap(), 4);
+                assert_eq!(first(&[]), None);
+                assert_eq!(first(&[1]), Some(2));
+            })
+        };
+        assert_eq!(values.0, [11, 22]);
+        let output = re.replace_all(&output, "file");
+        assert_eq!(output.matches(" file {\n").count(), 6, "{}", output);
+        assert!(output.contains(
+            "debug_log::tests::group_fn::Values::first_mut file {
+    [file] return = 1
+}
+debug_log::tests::group_fn::pick file {
+    [file] v = [
+        11,
+        2,
+    ]
+    [file] return = 2
+}
+"
+        ));
+        assert!(output.contains("    [file] return = Err(\n        ParseIntError"));
+        assert!(output.contains("    [file] return = Ok(\n        4,\n    )\n"));
+        assert!(output.contains("    [file] return = None\n"));
+        assert!(output.contains("    [file] return = Some(\n        2,\n    )\n"));
+    }
+
+    #[test]
+    fn group_scope_closes_on_panic() {
+        let _lock = lock_test();
+        let entries = collect(|| {
+            let result = std::panic::catch_unwind(|| {
+                crate::group_scope("Outer", || {
+                    crate::group_scope("Inner", || panic!("boom"));
+                })
+            });
+            assert!(result.is_err());
+            assert_eq!(crate::get_level(), 0);
+        });
+        assert_eq!(entries, ["Outer {", "    Inner {", "    }", "}"]);
+    }
+
+    #[test]
+    fn timed_group() {
+        let _lock = lock_test();
+        let entries = collect(|| {
+            crate::group_timed!("Timed");
+            {
+                group!("Untimed");
+                std::thread::sleep(std::time::Duration::from_millis(2));
+            }
+        });
+        assert_eq!(entries[2], "    }");
+        let closing = regex::Regex::new(r"^\} // [0-9.]+(µs|ms|s)$").unwrap();
+        assert!(closing.is_match(&entries[3]), "{}", entries[3]);
+    }
+
+    #[test]
+    fn duration_format() {
+        assert_eq!(crate::debug::format_duration(0.0123), "12.3µs");
+        assert_eq!(crate::debug::format_duration(12.44), "12.4ms");
+        assert_eq!(crate::debug::format_duration(1234.), "1.23s");
+    }
+
+    #[test]
+    #[cfg(feature = "log-compat")]
+    fn log_backend() {
+        static RECORDS: Mutex<Vec<String>> = Mutex::new(Vec::new());
+        struct TestLogger;
+        impl log::Log for TestLogger {
+            fn enabled(&self, metadata: &log::Metadata) -> bool {
+                metadata.target() == "debug_log"
+            }
+
+            fn log(&self, record: &log::Record) {
+                if self.enabled(record.metadata()) {
+                    RECORDS.lock().unwrap().push(record.args().to_string());
+                }
+            }
+
+            fn flush(&self) {}
+        }
+
+        let _lock = lock_test();
+        log::set_logger(&TestLogger).unwrap();
+        log::set_max_level(log::LevelFilter::Debug);
+        let _debug = crate::with_debug("*");
+        {
+            group!("A Group");
+            group!("B Group");
+            debug_dbg!(&[1]);
+        }
+        crate::set_backend(crate::Backend::Stderr);
+        debug_log!("not recorded");
+        crate::set_backend(crate::Backend::Log);
+
+        let records = RECORDS.lock().unwrap();
+        assert_eq!(records.len(), 7);
+        assert_eq!(records[0], "A Group {");
+        assert_eq!(records[1], "    B Group {");
+        assert!(records[2].starts_with("        [src/lib.rs:"));
+        assert_eq!(records[3], "            1,");
+        assert_eq!(records[4], "        ]");
+        assert_eq!(records[5], "    }");
+        assert_eq!(records[6], "}");
+        assert!(records.iter().all(|x| !x.contains('\x1b')));
+    }
+
+    #[test]
+    fn capture() {
+        let _lock = lock_test();
+        let _debug = crate::with_debug("*");
+        let output = crate::capture(|| {
+            group!("A Group");
+            debug_log!("Hi");
+            {
+                group!("B Group");
+                debug_dbg!(&[1, 2]);
+            }
+        });
+        assert_eq!(
+            strip_lines(&output),
+            "A Group {\n    [src/lib.rs] Hi\n    B Group {\n        [src/lib.rs] &[1, 2] = [\n            1,\n            2,\n        ]\n    }\n}\n"
+        );
+
+        crate::set_debug("not_this_file");
+        let output = crate::capture(|| debug_log!("Hi"));
+        assert_eq!(output, "");
+    }
+
+    #[test]
+    fn log_file() {
+        let _lock = lock_test();
+        let _debug = crate::with_debug("*");
+        let path = std::env::temp_dir().join(format!("debug-log-test-{}.log", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        crate::set_log_file(Some(path.clone()));
+        log_nested();
+        let content = std::fs::read_to_string(&path).unwrap();
+        crate::set_log_file(None);
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(strip_lines(&content), LOG_FILE_CONTENT);
+    }
+
+    const LOG_FILE_CONTENT: &str = "A Group {\n    [src/lib.rs] Hi\n    B Group {\n        [src/lib.rs] &[1, 2] = [\n            1,\n            2,\n        ]\n    }\n}\n[src/lib.rs] Bye\n";
+
+    fn log_nested() {
+        {
+            group!("A Group");
+            debug_log!("Hi");
+            {
+                group!("B Group");
+                debug_dbg!(&[1, 2]);
+            }
+        }
+        debug_log!("Bye");
+    }
+
+    /// Run by [`log_file_stderr`] in a child process
+    #[test]
+    #[ignore]
+    fn log_file_child() {
+        let Some(path) = std::env::var_os("DEBUG_LOG_FILE_CHILD") else {
+            return;
+        };
+
+        let _debug = crate::with_debug("*");
+        crate::set_backend(crate::Backend::Stderr);
+        crate::set_log_file(Some(path.into()));
+        log_nested();
+    }
+
+    fn run_log_file_child(path: &std::path::Path) -> String {
+        let output = std::process::Command::new(std::env::current_exe().unwrap())
+            .args([
+                "tests::log_file_child",
+                "--exact",
+                "--ignored",
+                "--nocapture",
+            ])
+            .env("DEBUG_LOG_FILE_CHILD", path)
+            .output()
+            .unwrap();
+        assert!(output.status.success());
+        String::from_utf8(output.stderr).unwrap()
+    }
+
+    #[test]
+    fn log_file_stderr() {
+        let path =
+            std::env::temp_dir().join(format!("debug-log-test-child-{}.log", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let stderr = run_log_file_child(&path);
+        let content = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(strip_lines(&content), LOG_FILE_CONTENT);
+        assert!(!stderr.contains("Group"), "{}", stderr);
+        assert!(!stderr.contains("] Hi"), "{}", stderr);
+
+        // A directory can't be opened as a file, so it falls back to stderr
+        let stderr = run_log_file_child(&std::env::temp_dir());
+        assert_eq!(
+            stderr.matches("debug-log: failed to open").count(),
+            1,
+            "{}",
+            stderr
+        );
+        assert!(stderr.contains("Writing to stderr instead"), "{}", stderr);
+        let start = stderr.find("A Group {").expect(&stderr);
+        let end = stderr.find("] Bye\n").expect(&stderr);
+        assert_eq!(
+            strip_lines(&stderr[start..end + "] Bye\n".len()]),
+            LOG_FILE_CONTENT
+        );
+    }
+
+    #[test]
+    fn panic_dump() {
+        let _lock = lock_test();
+        let _debug = crate::with_debug("*");
+        crate::enable_panic_dump(4);
+        let output = crate::capture(|| {
+            let _ = std::panic::catch_unwind(|| {
+                group!("A");
+                debug_log!("1");
+                group!("B");
+                debug_log!("2");
+                debug_log!("3");
+                panic!("boom");
+            });
+        });
+        crate::enable_panic_dump(0);
+        assert_eq!(
+            strip_lines(&output),
+            "... earlier output truncated\n    [src/lib.rs] 1\n    B {\n        [src/lib.rs] 2\n        [src/lib.rs] 3\n"
+        );
+
+        crate::enable_panic_dump(8);
+        let output = crate::capture(|| {
+            let _ = std::panic::catch_unwind(|| {
+                group!("A");
+                debug_log!("1");
+                panic!("boom");
+            });
+        });
+        crate::enable_panic_dump(0);
+        assert_eq!(strip_lines(&output), "A {\n    [src/lib.rs] 1\n");
+
+        crate::set_format(crate::Format::Json);
+        crate::enable_panic_dump(2);
+        let output = crate::capture(|| {
+            let _ = std::panic::catch_unwind(|| {
+                group!("A");
+                debug_log!("1");
+                debug_log!("2");
+                panic!("boom");
+            });
+        });
+        crate::enable_panic_dump(0);
+        crate::set_format(crate::Format::Text);
+        let types: Vec<_> = output
+            .lines()
+            .map(|x| serde_json::from_str::<serde_json::Value>(x).unwrap()["type"].clone())
+            .collect();
+        assert_eq!(types, ["truncated", "log", "log"]);
+    }
+
+    #[test]
+    fn close_labels() {
+        let _lock = lock_test();
+        crate::set_close_labels(true);
+        let entries = collect(|| {
+            group!("A Group");
+            {
+                group!("B Group");
+                {
+                    group!("C Group {}", "x".repeat(100));
+                }
+            }
+        });
+        crate::set_close_labels(false);
+        let c = format!("C Group {}", "x".repeat(31));
+        assert_eq!(
+            entries[3..],
+            [
+                format!("        }} // {}…", c),
+                "    } // B Group".to_string(),
+                "} // A Group".to_string()
+            ]
+        );
+    }
+
+    #[test]
+    fn dbg_compact() {
+        let _lock = lock_test();
+        // `[src/lib.rs:1234] [7; 7] = [7, 7, 7, 7, 7, 7, 7]` is 48 chars wide, so it
+        // only fits when it's not indented
+        crate::set_max_line_width(48);
+        let entries = collect(|| {
+            let (a, b) = debug_dbg_compact!((1, 2), [0; 20]);
+            assert_eq!((a, b.len()), ((1, 2), 20));
+            debug_dbg_compact!([7; 7]);
+            group!("A");
+            debug_dbg_compact!([7; 7]);
+        });
+        crate::set_max_line_width(120);
+        let entries: Vec<_> = entries.iter().map(|x| strip_lines(x)).collect();
+        assert_eq!(entries[0], "[src/lib.rs] (1, 2) = (1, 2)");
+        assert!(entries[1].starts_with("[src/lib.rs] [0; 20] = [\n    0,\n"));
+        assert_eq!(entries[2], "[src/lib.rs] [7; 7] = [7, 7, 7, 7, 7, 7, 7]");
+        assert!(entries[4].starts_with("    [src/lib.rs] [7; 7] = [\n        7,\n"));
+    }
+
+    /// Run by [`target`] in a child process
+    #[test]
+    #[ignore]
+    fn target_child() {
+        if std::env::var_os("DEBUG_LOG_TARGET_CHILD").is_none() {
+            return;
+        }
+
+        let _debug = crate::with_debug("*");
+        crate::set_backend(crate::Backend::Stderr);
+        crate::set_target(crate::Target::Stdout);
+        debug_log!("to stdout");
+        crate::set_target(crate::Target::Stderr);
+        debug_log!("to stderr");
+    }
+
+    /// Run by [`captured`] in a child process
+    #[test]
+    #[ignore]
+    fn captured_child() {
+        if std::env::var_os("DEBUG_LOG_CAPTURED_CHILD").is_none() {
+            return;
+        }
+
+        let _debug = crate::with_debug("*");
+        crate::set_backend(crate::Backend::Stderr);
+        debug_log!("hidden on stderr");
+        crate::set_target(crate::Target::Stdout);
+        debug_log!("hidden on stdout");
+        crate::set_target(crate::Target::Stderr);
+    }
+
+    #[test]
+    fn captured() {
+        // Without `--nocapture`, the output of the passing tests is hidden
+        let output = std::process::Command::new(std::env::current_exe().unwrap())
+            .args(["tests::captured_child", "--exact", "--ignored"])
+            .env("DEBUG_LOG_CAPTURED_CHILD", "1")
+            .output()
+            .unwrap();
+        let stdout = String::from_utf8(output.stdout).unwrap();
+        let stderr = String::from_utf8(output.stderr).unwrap();
+        assert!(stdout.contains("1 passed"), "{}", stdout);
+        assert!(!stdout.contains("hidden on"), "{}", stdout);
+        assert!(!stderr.contains("hidden on"), "{}", stderr);
+    }
+
+    #[test]
+    fn target() {
+        let output = std::process::Command::new(std::env::current_exe().unwrap())
+            .args(["tests::target_child", "--exact", "--ignored", "--nocapture"])
+            .env("DEBUG_LOG_TARGET_CHILD", "1")
+            .output()
+            .unwrap();
+        let stdout = String::from_utf8(output.stdout).unwrap();
+        let stderr = String::from_utf8(output.stderr).unwrap();
+        assert!(stdout.contains("] to stdout\n"), "{}", stdout);
+        assert!(!stdout.contains("to stderr"), "{}", stdout);
+        assert!(stderr.contains("] to stderr\n"), "{}", stderr);
+        assert!(!stderr.contains("to stdout"), "{}", stderr);
+    }
+
+    #[test]
+    fn group_begin_end() {
+        fn begin() {
+            group_begin!("Transaction {}", 1);
+        }
+
+        fn commit() {
+            debug_log!("Commit");
+            group_end!("Transaction {}", 1);
+        }
+
+        let _lock = lock_test();
+        let entries = collect(|| {
+            group!("A");
+            begin();
+            for i in 0..2 {
+                if i == 1 {
+                    commit();
+                }
+            }
+            debug_log!("After");
+        });
+        let entries: Vec<_> = entries.iter().map(|x| strip_lines(x)).collect();
+        assert_eq!(
+            entries,
+            [
+                "A {",
+                "    Transaction 1 {",
+                "        [src/lib.rs] Commit",
+                "    }",
+                "    [src/lib.rs] After",
+                "}"
+            ]
+        );
+
+        let entries = collect(|| {
+            group_begin!("B");
+            group_end!("C");
+        });
+        let entries: Vec<_> = entries.iter().map(|x| strip_lines(x)).collect();
+        assert_eq!(
+            entries,
+            [
+                "B {",
+                "    [src/lib.rs] warning: group_end!(\"C\") closed the group \"B\"",
+                "}"
+            ]
+        );
+
+        // Unbalanced `group_end!` doesn't close the groups of `group!`
+        let entries = collect(|| {
+            group!("D");
+            group_end!();
+            group_end!();
+            assert_eq!(crate::get_level(), 1);
+        });
+        let entries: Vec<_> = entries.iter().map(|x| strip_lines(x)).collect();
+        assert_eq!(
+            entries,
+            [
+                "D {",
+                "    [src/lib.rs] warning: group_end!() without a matching group_begin!()",
+                "}"
+            ]
+        );
+
+        // A filtered out group isn't opened, so its `group_end!` closes nothing
+        let entries = collect(|| {
+            group!("E");
+            {
+                let _debug = crate::with_debug("not_this_file");
+                group_begin!("F");
+            }
+            group_end!();
+            assert_eq!(crate::get_level(), 1);
+        });
+        assert_eq!(entries, ["E {", "}"]);
+    }
+
+    /// Pending on the first poll
+    struct YieldNow(bool);
+
+    impl std::future::Future for YieldNow {
+        type Output = ();
+
+        fn poll(
+            mut self: std::pin::Pin<&mut Self>,
+            cx: &mut std::task::Context<'_>,
+        ) -> std::task::Poll<()> {
+            if self.0 {
+                return std::task::Poll::Ready(());
+            }
+            self.0 = true;
+            cx.waker().wake_by_ref();
+            std::task::Poll::Pending
+        }
+    }
+
+    #[test]
+    fn debug_group_future() {
+        use crate::DebugGroupExt;
+        use std::future::Future;
+        use std::pin::Pin;
+        use std::task::{Context, Poll, Waker};
+
+        async fn task(id: u32) -> u32 {
+            for step in 0..2 {
+                group!("step {}", step);
+                debug_log!("{} before", id);
+                YieldNow(false).await;
+                debug_log!("{} after", id);
+            }
+            id
+        }
+
+        let _lock = lock_test();
+        let entries = collect(|| {
+            group!("Runtime");
+            // Poll the tasks in turns on the current thread
+            let mut tasks: Vec<Pin<Box<dyn Future<Output = u32>>>> = vec![
+                Box::pin(task(1).debug_group("Task 1")),
+                Box::pin(task(2).debug_group("Task 2")),
+            ];
+            let mut cx = Context::from_waker(Waker::noop());
+            let mut done = Vec::new();
+            while !tasks.is_empty() {
+                tasks.retain_mut(|task| match task.as_mut().poll(&mut cx) {
+                    Poll::Ready(id) => {
+                        done.push(id);
+                        false
+                    }
+                    Poll::Pending => true,
+                });
+            }
+            assert_eq!(done, [1, 2]);
+            assert_eq!(crate::get_level(), 1);
+        });
+        let entries: Vec<_> = entries.iter().map(|x| strip_lines(x)).collect();
+        // Each task keeps its own nesting, even though their entries are interleaved
+        assert_eq!(
+            entries,
+            [
+                "Runtime {",
+                "    Task 1 {",
+                "        step 0 {",
+                "            [src/lib.rs] 1 before",
+                "    Task 2 {",
+                "        step 0 {",
+                "            [src/lib.rs] 2 before",
+                "            [src/lib.rs] 1 after",
+                "        }",
+                "        step 1 {",
+                "            [src/lib.rs] 1 before",
+                "            [src/lib.rs] 2 after",
+                "        }",
+                "        step 1 {",
+                "            [src/lib.rs] 2 before",
+                "            [src/lib.rs] 1 after",
+                "        }",
+                "    }",
+                "            [src/lib.rs] 2 after",
+                "        }",
+                "    }",
+                "}",
+            ]
+        );
+    }
+
+    #[test]
+    fn debug_group_threads() {
+        use crate::DebugGroupExt;
+        use std::collections::VecDeque;
+        use std::future::Future;
+        use std::pin::Pin;
+        use std::task::{Context, Waker};
+
+        type Task = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+        async fn task(id: u32) {
+            for step in 0..3 {
+                group!("{} step {}", id, step);
+                debug_log!("{} before", id);
+                YieldNow(false).await;
+                debug_log!("{} after", id);
+            }
+        }
+
+        let _lock = lock_test();
+        let entries = collect(|| {
+            let queue: Mutex<VecDeque<Task>> = Mutex::new(
+                (0..4)
+                    .map(|id| Box::pin(task(id).debug_group(format!("Task {}", id))) as Task)
+                    .collect(),
+            );
+            // The tasks move between the threads, like on a multi-threaded executor
+            std::thread::scope(|scope| {
+                for _ in 0..3 {
+                    scope.spawn(|| {
+                        let mut cx = Context::from_waker(Waker::noop());
+                        loop {
+                            let Some(mut task) = queue.lock().unwrap().pop_front() else {
+                                break;
+                            };
+                            if task.as_mut().poll(&mut cx).is_pending() {
+                                queue.lock().unwrap().push_back(task);
+                            }
+                            assert_eq!(crate::get_level(), 0);
+                        }
+                    });
+                }
+                scope.spawn(|| {
+                    for i in 0..20 {
+                        debug_log!("sync {}", i);
+                        assert_eq!(crate::get_level(), 0);
+                        std::thread::yield_now();
+                    }
+                });
+            });
+            assert_eq!(crate::get_level(), 0);
+        });
+
+        let entries: Vec<_> = entries.iter().map(|x| strip_lines(x)).collect();
+        let count = |f: &dyn Fn(&str) -> bool| entries.iter().filter(|x| f(x)).count();
+        assert_eq!(count(&|x| x.starts_with("[src/lib.rs] sync ")), 20);
+        for id in 0..4 {
+            assert_eq!(count(&|x| x == format!("Task {} {{", id)), 1);
+            for step in 0..3 {
+                assert_eq!(count(&|x| x == format!("    {} step {} {{", id, step)), 1);
+            }
+            for when in ["before", "after"] {
+                let entry = format!("        [src/lib.rs] {} {}", id, when);
+                assert_eq!(count(&|x| x == entry), 3, "{:#?}", entries);
+            }
+        }
+        assert_eq!(count(&|x| x == "}"), 4, "{:#?}", entries);
+        assert_eq!(count(&|x| x == "    }"), 12, "{:#?}", entries);
+        assert_eq!(entries.len(), 20 + 4 * (2 + 3 * 4));
+    }
+
+    #[test]
+    fn dedup() {
+        let _lock = lock_test();
+        crate::set_dedup(true);
+        let entries = collect(|| {
+            for _ in 0..5 {
+                debug_log!("retry");
+            }
+            for x in [1, 1, 2] {
+                debug_dbg!(&[x]);
+            }
+            debug_log!("once");
+            let retry = || {
+                for _ in 0..2 {
+                    debug_log!("retry");
+                }
+            };
+            for _ in 0..2 {
+                group!("A");
+                retry();
+            }
+            retry();
+        });
+        crate::set_dedup(false);
+        let entries: Vec<_> = entries.iter().map(|x| strip_lines(x)).collect();
+        assert_eq!(
+            entries,
+            [
+                "[src/lib.rs] retry",
+                "... last message repeated 4 times",
+                "[src/lib.rs] &[x] = [\n    1,\n]",
+                "... last message repeated 1 time",
+                "[src/lib.rs] &[x] = [\n    2,\n]",
+                "[src/lib.rs] once",
+                "A {",
+                "    [src/lib.rs] retry",
+                "    ... last message repeated 1 time",
+                "}",
+                "A {",
+                "    [src/lib.rs] retry",
+                "    ... last message repeated 1 time",
+                "}",
+                "[src/lib.rs] retry",
+                // Flushed when the sink is swapped
+                "... last message repeated 1 time",
+            ]
+        );
+    }
+
+    #[test]
+    fn capture_nested() {
+        let _lock = lock_test();
+        let _debug = crate::with_debug("*");
+        let mut inner = String::new();
+        let outer = crate::capture(|| {
+            debug_log!("outer 1");
+            inner = crate::capture(|| debug_log!("inner"));
+            debug_log!("outer 2");
+        });
+        assert_eq!(strip_lines(&inner), "[src/lib.rs] inner\n");
+        assert_eq!(
+            strip_lines(&outer),
+            "[src/lib.rs] outer 1\n[src/lib.rs] outer 2\n"
+        );
+    }
+
+    #[test]
+    fn capture_restores_on_panic() {
+        let _lock = lock_test();
+        let _debug = crate::with_debug("*");
+        let result = std::panic::catch_unwind(|| crate::capture(|| panic!("boom")));
+        assert!(result.is_err());
+        assert_eq!(crate::capture(|| debug_log!("Hi")).lines().count(), 1);
+        assert_eq!(collect(|| debug_log!("Hi")).len(), 1);
+    }
+
+    #[test]
+    fn no_arg_macros() {
+        let _lock = lock_test();
+        let log = || {
+            group!();
+            debug_log!();
+            debug_dbg!();
+        };
+        let _debug = crate::with_debug("lib.rs");
+        let output = strip_lines(&crate::capture(|| {
+            log();
+            crate::set_debug("not_this_file");
+            log();
+        }));
+        assert_eq!(
+            output,
+            "debug_log::tests::no_arg_macros src/lib.rs {\n    [src/lib.rs] \n    [src/lib.rs] \n}\n"
+        );
+    }
+
+    #[test]
+    fn dbg_returns_value() {
+        fn double(x: i32) -> i32 {
+            x * 2
+        }
+
+        let _lock = lock_test();
+        let entries = collect(|| {
+            let a = debug_dbg!(1 + 2);
+            assert_eq!(a, 3);
+            assert_eq!(double(debug_dbg!(a)), 6);
+            let s = debug_dbg!(String::from("owned"));
+            let (s, n) = debug_dbg!(s, 1);
+            assert_eq!(s, "owned");
+            assert_eq!(n, 1);
+            let v = vec![1];
+            let r = debug_dbg!(&v);
+            assert_eq!(r, &v);
+        });
+        let entries: Vec<_> = entries.iter().map(|x| strip_lines(x)).collect();
+        assert_eq!(
+            entries,
+            [
+                "[src/lib.rs] 1 + 2 = 3",
+                "[src/lib.rs] a = 3",
+                "[src/lib.rs] String::from(\"owned\") = \"owned\"",
+                "[src/lib.rs] s = \"owned\"",
+                "[src/lib.rs] 1 = 1",
+                "[src/lib.rs] &v = [\n    1,\n]",
+            ]
+        );
+    }
+
+    #[test]
+    fn dbg_evaluates_when_filtered_out() {
+        let _lock = lock_test();
+        let _debug = crate::with_debug("not_this_file");
+        let mut count = 0;
+        let mut inc = || {
+            count += 1;
+            count
+        };
+        assert_eq!(debug_dbg!(inc()), 1);
+        assert_eq!(debug_dbg!(inc(), inc()), (2, 3));
+        assert_eq!(count, 3);
+    }
+
+    #[test]
+    fn truncate_value() {
+        use crate::debug::truncate_value;
+
+        let value = format!("{:#?}", [1, 2, 3]);
+        assert_eq!(truncate_value(&value, 5, usize::MAX), value);
+        assert_eq!(
+            truncate_value(&value, 4, usize::MAX),
+            "[\n    1,\n    2,\n    ... (1 more line truncated)\n]"
+        );
+        assert_eq!(
+            truncate_value(&value, usize::MAX, 10),
+            "[\n    1,\n    ... (2 more lines truncated)\n]"
+        );
+        assert_eq!(
+            truncate_value("\"abcdef\"", usize::MAX, 4),
+            "\"abc... (4 more chars truncated)"
+        );
+
+        // Fewer than 2 lines keeps the first and the last line
+        for max_lines in [0, 1] {
+            assert_eq!(truncate_value("1", max_lines, usize::MAX), "1");
+            assert_eq!(truncate_value("[\n]", max_lines, usize::MAX), "[\n]");
+            assert_eq!(
+                truncate_value("[\n    1,\n]", max_lines, usize::MAX),
+                "[\n    ... (1 more line truncated)\n]"
+            );
+            assert_eq!(
+                truncate_value(&value, max_lines, usize::MAX),
+                "[\n    ... (3 more lines truncated)\n]"
+            );
+        }
+
+        // Only the chars are over the limit
+        assert_eq!(truncate_value(&value, usize::MAX, value.len()), value);
+        assert_eq!(
+            truncate_value(&value, usize::MAX, value.len() - 1),
+            "[\n    1,\n    2,\n    ... (1 more line truncated)\n]"
+        );
+        assert_eq!(truncate_value("[\n]", usize::MAX, 3), "[\n]");
+        assert_eq!(
+            truncate_value("[\n]", usize::MAX, 2),
+            "[\n... (1 more char truncated)"
+        );
+        assert_eq!(
+            truncate_value("[1, 2, 3,\n    4]", 2, 4),
+            "[1, ... (12 more chars truncated)"
+        );
+        assert_eq!(
+            truncate_value("[1, 2, 3,\n    4,\n]", 2, 4),
+            "[1, ... (14 more chars truncated)"
+        );
+    }
+
+    #[test]
+    fn truncate_dbg() {
+        let _lock = lock_test();
+        let entries = collect(|| {
+            crate::set_max_value_size(3, usize::MAX);
+            group!("A Group");
+            debug_dbg!(&[1], &[1, 2]);
+            crate::set_max_value_size(0, usize::MAX);
+            debug_dbg!(1);
+            crate::set_max_value_size(usize::MAX, usize::MAX);
+        });
+        let entries: Vec<_> = entries.iter().map(|x| strip_lines(x)).collect();
+        assert_eq!(entries[3], "    [src/lib.rs] 1 = 1");
+        assert_eq!(entries[1], "    [src/lib.rs] &[1] = [\n        1,\n    ]");
+        assert_eq!(
+            entries[2],
+            "    [src/lib.rs] &[1, 2] = [\n        1,\n        ... (1 more line truncated)\n    ]"
+        );
+    }
+
+    #[test]
+    fn group_filter() {
+        let _lock = lock_test();
+        let _debug = crate::with_debug("group:apply_pending,other.rs");
+        let output = strip_lines(&crate::capture(|| {
+            debug_log!("outside");
+            {
+                group!("apply_pending {}", 1);
+                debug_dbg!(1);
+                {
+                    group!("nested");
+                    debug_log!("inside");
+                }
+            }
+            {
+                group!("other");
+                debug_log!("outside");
+            }
+            debug_log!("outside");
+        }));
+        assert_eq!(
+            output,
+            "apply_pending 1 {\n    [src/lib.rs] 1 = 1\n    nested {\n        [src/lib.rs] inside\n    }\n}\n"
+        );
+        assert!(crate::should_log("src/other.rs", 1, ""));
+        assert!(!crate::should_log("src/lib.rs", 1, ""));
+    }
+
+    #[test]
+    fn module_filter() {
+        let _lock = lock_test();
+        let _debug = crate::with_debug("mod:tests::inner,other.rs");
+        assert!(crate::should_log(
+            "src/lib.rs",
+            1,
+            "debug_log::tests::inner"
+        ));
+        assert!(crate::should_log(
+            "src/lib.rs",
+            1,
+            "debug_log::tests::inner::deep"
+        ));
+        assert!(!crate::should_log(
+            "src/lib.rs",
+            1,
+            "debug_log::tests::inner2"
+        ));
+        assert!(!crate::should_log("src/lib.rs", 1, "debug_log::tests"));
+        assert!(crate::should_log("src/other.rs", 1, "debug_log::tests"));
+
+        mod inner {
+            pub fn log() {
+                crate::debug_log!("inner");
+            }
+        }
+
+        let output = crate::capture(|| {
+            debug_log!("outer");
+            inner::log();
+        });
+        assert_eq!(strip_lines(&output), "[src/lib.rs] inner\n");
+    }
+
+    #[test]
+    fn windows_paths() {
+        let _lock = lock_test();
+        for debug in ["src/state", "src\\state", "src/state/tree.rs:10-20"] {
+            let _debug = crate::with_debug(debug);
+            assert!(
+                crate::should_log("src\\state\\tree.rs", 12, ""),
+                "{}",
+                debug
+            );
+            assert!(crate::should_log("src/state/tree.rs", 12, ""), "{}", debug);
+            assert!(
+                !crate::should_log("src\\other\\tree.rs", 12, ""),
+                "{}",
+                debug
+            );
+        }
+
+        let entries = collect(|| crate::log_message("src\\state\\tree.rs:12", "Hi".into()));
+        assert_eq!(entries, ["[src/state/tree.rs:12] Hi"]);
+    }
+
+    #[test]
+    fn parse_filter() {
+        use crate::debug::filter::{Filter, Pattern};
+
+        let file = |path: &str, lines: &[(u32, u32)]| Pattern::File {
+            path: path.to_string(),
+            lines: lines.to_vec(),
+        };
+        for (value, patterns) in [
+            ("", &[][..]),
+            (" ", &[]),
+            ("0", &[]),
+            ("false", &[]),
+            ("off", &[]),
+            ("OFF", &[]),
+            ("*", &[Pattern::All]),
+            ("1", &[Pattern::All]),
+            ("true", &[Pattern::All]),
+            ("all", &[Pattern::All]),
+            ("All", &[Pattern::All]),
+            ("alloc", &[file("alloc", &[])]),
+            ("12", &[file("12", &[])]),
+            ("10-20", &[file("10-20", &[])]),
+        ] {
+            assert_eq!(Filter::parse(value).patterns, patterns, "{:?}", value);
+        }
+        assert_eq!(
+            Filter::parse("a.rs,b.rs:12,c.rs:200-400,300-,d.rs:5-").patterns,
+            [
+                file("a.rs", &[]),
+                file("b.rs", &[(12, 12)]),
+                file("c.rs", &[(200, 400), (300, u32::MAX)]),
+                file("d.rs", &[(5, u32::MAX)]),
+            ]
+        );
+        assert_eq!(
+            Filter::parse("mod:a::b, group:x").patterns,
+            [
+                Pattern::Module(vec!["a".to_string(), "b".to_string()]),
+                Pattern::Group("x".to_string())
+            ]
+        );
+
+        // Ranges only follow a file with ranges
+        assert_eq!(
+            Filter::parse("a.rs,12").patterns,
+            [file("a.rs", &[]), file("12", &[])]
+        );
+        assert_eq!(
+            Filter::parse("group:x,10-20").patterns,
+            [Pattern::Group("x".to_string()), file("10-20", &[])]
+        );
+        assert!(Filter::parse("12").matches("src/v12/a.rs", 1, ""));
+
+        assert_eq!(
+            Filter::parse("src\\state,src\\a.rs:12").patterns,
+            [file("src/state", &[]), file("src/a.rs", &[(12, 12)])]
+        );
+
+        let filter = Filter::parse("a.rs:20,b.rs:10-20,30-");
+        assert!(filter.matches("src/a.rs", 20, ""));
+        assert!(!filter.matches("src/a.rs", 201, ""));
+        assert!(!filter.matches("src/a.rs", 2, ""));
+        assert!(filter.matches("src/b.rs", 10, ""));
+        assert!(filter.matches("src/b.rs", 20, ""));
+        assert!(!filter.matches("src/b.rs", 21, ""));
+        assert!(filter.matches("src/b.rs", 3000, ""));
+    }
+
+    #[test]
+    fn line_filter() {
+        let _lock = lock_test();
+        let line = line!();
+        let _debug = crate::with_debug(&format!("lib.rs:{},{}-", line + 3, line + 5));
+        let output = crate::capture(|| {
+            debug_log!("a");
+            debug_log!("b");
+            debug_log!("c");
+            debug_log!("d");
+        });
+        assert_eq!(
+            output,
+            format!(
+                "[src/lib.rs:{}] a\n[src/lib.rs:{}] c\n[src/lib.rs:{}] d\n",
+                line + 3,
+                line + 5,
+                line + 6
+            )
+        );
+    }
+
+    #[test]
+    fn count() {
+        let _lock = lock_test();
+        let entries = collect(|| {
+            for _ in 0..2 {
+                crate::debug_count!("a");
+            }
+            crate::debug_count!("b");
+            crate::debug_count_reset!("a");
+            crate::debug_count!("a");
+            {
+                let _debug = crate::with_debug("not_this_file");
+                crate::debug_count!("b");
+            }
+            crate::debug_count!(String::from("b"));
+            crate::debug_count_reset!("b");
+            crate::debug_count_reset!("a");
+        });
+        let entries: Vec<_> = entries.iter().map(|x| strip_lines(x)).collect();
+        assert_eq!(
+            entries,
+            [
+                "[src/lib.rs] a: 1",
+                "[src/lib.rs] a: 2",
+                "[src/lib.rs] b: 1",
+                "[src/lib.rs] a: 1",
+                "[src/lib.rs] b: 2",
+            ]
+        );
+    }
+
+    #[test]
+    fn hex() {
+        let _lock = lock_test();
+        let bytes: Vec<u8> = b"Hello, world!\n\x00\x01\xffabc".to_vec();
+        let entries = collect(|| {
+            group!("A Group");
+            crate::debug_hex!(&bytes);
+            crate::debug_hex!(&bytes, max = 4);
+            crate::debug_hex!(b"");
+        });
+        let entries: Vec<_> = entries.iter().map(|x| strip_lines(x)).collect();
+        assert_eq!(
+            entries[1],
+            "    [src/lib.rs] &bytes = 20 bytes\n    \
+             00000000  48 65 6c 6c 6f 2c 20 77  6f 72 6c 64 21 0a 00 01  |Hello, world!...|\n    \
+             00000010  ff 61 62 63                                       |.abc|"
+        );
+        assert_eq!(
+            entries[2],
+            "    [src/lib.rs] &bytes = 20 bytes\n    \
+             00000000  48 65 6c 6c                                       |Hell|\n    \
+             ... (16 more bytes truncated)"
+        );
+        assert_eq!(entries[3], "    [src/lib.rs] b\"\" = 0 bytes");
+    }
+
+    #[test]
+    fn table() {
+        #[derive(Debug)]
+        enum Status {
+            Done,
+            Pending,
+        }
+
+        let _lock = lock_test();
+        let spans = vec![(1, 100, Status::Done), (20, 3, Status::Pending)];
+        let empty: Vec<(u32, u32, Status)> = Vec::new();
+        let entries = collect(|| {
+            group!("A Group");
+            crate::debug_table!(&spans, [s => s.0, s => s.1, s => format!("{:?}", s.2)]);
+            crate::set_max_cell_width(5);
+            crate::debug_table!(&spans, [s => format!("{:?}", s.2)]);
+            crate::set_max_cell_width(40);
+            crate::debug_table!(&empty, [s => s.0]);
+        });
+        let entries: Vec<_> = entries.iter().map(|x| strip_lines(x)).collect();
+        assert_eq!(
+            entries[1],
+            "    [src/lib.rs] &spans = 2 rows\n    \
+             | s.0 | s.1 | format!(\"{:?}\", s.2) |\n    \
+             |-----|-----|----------------------|\n    \
+             | 1   | 100 | Done                 |\n    \
+             | 20  | 3   | Pending              |"
+        );
+        assert_eq!(
+            entries[2],
+            "    [src/lib.rs] &spans = 2 rows\n    \
+             | form… |\n    \
+             |-------|\n    \
+             | Done  |\n    \
+             | Pend… |"
+        );
+        assert_eq!(
+            entries[3],
+            "    [src/lib.rs] &empty = 0 rows\n    | s.0 |\n    |-----|"
+        );
+    }
+
+    #[test]
+    fn table_is_lazy() {
+        let _lock = lock_test();
+        let _debug = crate::with_debug("not_this_file");
+        crate::debug_table!(
+            (0..1).map(|_: i32| -> i32 { unreachable!() }),
+            [x => x]
+        );
+    }
+
+    #[test]
+    fn conditional() {
+        let _lock = lock_test();
+        let entries = collect(|| {
+            for i in 0..4 {
+                crate::debug_log_if!(i % 2 == 0, "i = {}", i);
+                crate::debug_dbg_if!(i == 3, i, i * 2);
+            }
+        });
+        let entries: Vec<_> = entries.iter().map(|x| strip_lines(x)).collect();
+        assert_eq!(
+            entries,
+            [
+                "[src/lib.rs] i = 0",
+                "[src/lib.rs] i = 2",
+                "[src/lib.rs] i = 3",
+                "[src/lib.rs] i * 2 = 6",
+            ]
+        );
+
+        let _debug = crate::with_debug("not_this_file");
+        let mut evaluated = false;
+        crate::debug_log_if!(
+            {
+                evaluated = true;
+                true
+            },
+            "Hi"
+        );
+        crate::debug_dbg_if!(
+            {
+                evaluated = true;
+                true
+            },
+            1
+        );
+        assert!(!evaluated);
+    }
+
+    #[test]
+    fn throttled() {
+        let _lock = lock_test();
+        let entries = collect(|| {
+            group!("Loop");
+            for i in 0..25 {
+                crate::debug_log_once!("once {}", i);
+                crate::debug_log_every!(10, "every {}", i);
+            }
+        });
+        let entries: Vec<_> = entries.iter().map(|x| strip_lines(x)).collect();
+        assert_eq!(
+            entries,
+            [
+                "Loop {",
+                "    [src/lib.rs] once 0",
+                "    [src/lib.rs] every 0",
+                "    [src/lib.rs] every 10 (skipped 9)",
+                "    [src/lib.rs] every 20 (skipped 9)",
+                "}",
+            ]
+        );
+    }
+
+    #[test]
+    fn json_format() {
+        let _lock = lock_test();
+        let entries = collect(|| {
+            crate::set_format(crate::Format::Json);
+            {
+                group!("A \"Group\"");
+                debug_log!("Hi\n\tthere");
+                {
+                    group!("B Group");
+                    debug_dbg!(&[1]);
+                }
+                crate::debug_count!("c");
+                crate::debug_count_reset!("c");
+            }
+            crate::set_format(crate::Format::Text);
+        });
+
+        let events: Vec<serde_json::Value> = entries
+            .iter()
+            .map(|x| serde_json::from_str(x).unwrap())
+            .collect();
+        let types: Vec<_> = events.iter().map(|x| x["type"].as_str().unwrap()).collect();
+        let depths: Vec<_> = events
+            .iter()
+            .map(|x| x["depth"].as_u64().unwrap())
+            .collect();
+        assert_eq!(
+            types,
+            [
+                "group_start",
+                "log",
+                "group_start",
+                "dbg",
+                "group_end",
+                "log",
+                "group_end"
+            ]
+        );
+        assert_eq!(depths, [0, 1, 1, 2, 1, 1, 0]);
+        assert_eq!(events[0]["label"], "A \"Group\"");
+        assert_eq!(events[1]["msg"], "Hi\n\tthere");
+        assert_eq!(events[1]["file"], "src/lib.rs");
+        assert!(events[1]["line"].as_u64().unwrap() > 0);
+        assert_eq!(events[3]["name"], "&[1]");
+        assert_eq!(events[3]["value"], "[\n    1,\n]");
+        assert_eq!(events[5]["msg"], "c: 1");
+    }
+
+    #[test]
+    fn disabled_path_does_not_allocate() {
+        let _lock = lock_test();
+        let bytes = [1u8, 2, 3];
+        let log = || {
+            group!("A Group {}", 1);
+            debug_log!("Hi {}", 1);
+            debug_dbg!(&bytes);
+            crate::debug_hex!(&bytes);
+            crate::debug_count!("count");
+            crate::debug_table!(&bytes, [x => x]);
+            crate::debug_log_every!(2, "every");
+        };
+
+        for filter in ["", "not_this_file"] {
+            let _debug = crate::with_debug(filter);
+            // Initialize the lazy statics first
+            log();
+            let before = ALLOCATIONS.with(|x| x.get());
+            log();
+            assert_eq!(ALLOCATIONS.with(|x| x.get()) - before, 0);
+        }
+    }
+
+    #[test]
+    fn custom_indent() {
+        let _lock = lock_test();
+        let log = || {
+            group!("A");
+            group!("B");
+            group!("C");
+            debug_dbg!(&[1]);
+            debug_log!("x\ny");
+        };
+
+        let output = |indent: &str| {
+            crate::set_indent(indent);
+            let _debug = crate::with_debug("*");
+            let output = crate::capture(log);
+            crate::set_indent("    ");
+            strip_lines(&output)
+        };
+        assert_eq!(
+            output("  "),
+            "A {\n  B {\n    C {\n      [src/lib.rs] &[1] = [\n          1,\n      ]\n      \
+             [src/lib.rs] x\n      y\n    }\n  }\n}\n"
+        );
+        assert_eq!(
+            output("│  "),
+            "A {\n│  B {\n│  │  C {\n│  │  │  [src/lib.rs] &[1] = [\n│  │  │      1,\n│  │  │  ]\n\
+             │  │  │  [src/lib.rs] x\n│  │  │  y\n│  │  }\n│  }\n}\n"
+        );
+    }
+
+    #[test]
+    fn scoped_debug() {
+        let _lock = lock_test();
+        let _outer = crate::with_debug("lib.rs");
+        assert!(crate::should_log("src/lib.rs", 1, ""));
+        assert!(!crate::should_log("src/other.rs", 1, ""));
+        {
+            let _inner = crate::with_debug("other.rs");
+            assert!(!crate::should_log("src/lib.rs", 1, ""));
+            assert!(crate::should_log("src/other.rs", 1, ""));
+        }
+        assert!(crate::should_log("src/lib.rs", 1, ""));
+
+        let result = std::panic::catch_unwind(|| {
+            let _inner = crate::with_debug("other.rs");
+            panic!("boom");
+        });
+        assert!(result.is_err());
+        assert!(crate::should_log("src/lib.rs", 1, ""));
+        assert!(!crate::should_log("src/other.rs", 1, ""));
+    }
+
+    #[test]
+    fn scoped_debug_is_serialized_across_threads() {
+        let _lock = lock_test();
+        let guard = crate::with_debug("lib.rs");
+        let handle = std::thread::spawn(|| {
+            let _guard = crate::with_debug("other.rs");
+            crate::should_log("src/other.rs", 1, "")
+        });
+        std::thread::sleep(std::time::Duration::from_millis(20));
+        assert!(crate::should_log("src/lib.rs", 1, ""));
+        drop(guard);
+        assert!(handle.join().unwrap());
+    }
+
+    #[derive(Debug)]
+    #[allow(dead_code)]
+    struct Large {
+        name: String,
+        values: Vec<(u32, String)>,
+    }
+
+    #[test]
+    fn parallel_entries_are_not_interleaved() {
+        let _lock = lock_test();
+        let entries = collect(|| {
+            let handles: Vec<_> = (0..8)
+                .map(|t| {
+                    std::thread::spawn(move || {
+                        let large = Large {
+                            name: format!("thread {}", t),
+                            values: (0..20).map(|i| (i, i.to_string())).collect(),
+                        };
+                        for _ in 0..20 {
+                            debug_dbg!(&large);
+                            debug_log!("multi\nline");
+                        }
+                    })
+                })
+                .collect();
+            for h in handles {
+                h.join().unwrap();
+            }
+        });
+
+        assert_eq!(entries.len(), 8 * 20 * 2);
+        for entry in entries {
+            assert!(entry.starts_with('['));
+            if entry.ends_with("multi\nline") {
+                continue;
+            }
+
+            let lines: Vec<_> = entry.lines().collect();
+            assert_eq!(lines.len(), 2 + 20 * 4 + 3);
+            assert_eq!(*lines.last().unwrap(), "}");
+            for line in &lines[1..] {
+                assert!(line.starts_with(' ') || line.starts_with('}'));
+            }
+        }
+    }
+}
+
+/// The macros compile to nothing in release mode, but the values are still evaluated
+#[cfg(all(test, not(debug_assertions)))]
+mod release_tests {
+    use crate::{
+        debug_count, debug_dbg, debug_dbg_compact, debug_hex, debug_log, debug_table, group,
+        group_begin, group_end, group_timed, DebugGroupExt,
+    };
+
+    #[test]
+    fn stubs() {
+        let _debug = crate::with_debug("*");
+        group!("A Group");
+        group_timed!("B Group");
+        group_begin!("C Group");
+        group_end!();
+        debug_log!("Hi {}", 1);
+        debug_count!();
+        debug_table!([1, 2], [x => x]);
+
+        let mut evaluated = 0;
+        let mut eval = |x: i32| {
+            evaluated += 1;
+            x
+        };
+        assert_eq!(debug_dbg!(eval(1)), 1);
+        assert_eq!(debug_dbg!(eval(2), eval(3)), (2, 3));
+        assert_eq!(debug_dbg_compact!(eval(4)), 4);
+        debug_hex!({
+            eval(5);
+            [0u8; 4]
+        });
+        assert_eq!(evaluated, 5);
+
+        assert_eq!(crate::group_scope("Scope", || 1 + 1), 2);
+        assert_eq!(crate::capture(|| debug_log!("Hi")), "");
+        assert_eq!(crate::get_level(), 0);
+
+        let mut cx = std::task::Context::from_waker(std::task::Waker::noop());
+        let mut fut = std::pin::pin!(async { 1 }.debug_group("Task"));
+        assert_eq!(
+            std::future::Future::poll(fut.as_mut(), &mut cx),
+            std::task::Poll::Ready(1)
+        );
+    }
+
+    #[cfg(feature = "macros")]
+    #[test]
+    fn group_fn() {
+        #[crate::group_fn(args, ret)]
+        fn factorial(n: u64) -> u64 {
+            if n <= 1 {
+                return 1;
+            }
+            n * factorial(n - 1)
+        }
+
+        assert_eq!(factorial(5), 120);
+
+        #[crate::group_fn(ret)]
+        fn pick(v: &mut [u32]) -> &mut u32 {
+            if v.len() > 1 {
+                return &mut v[1];
+            }
+            &mut v[0]
+        }
+
+        #[crate::group_fn(ret)]
+        fn parse(s: &str) -> Result<u32, Box<dyn std::error::Error>> {
+            let n: u32 = s.parse()?;
+            Ok(n * 2)
+        }
+
+        let mut v = vec![1, 2];
+        *pick(&mut v) += 1;
+        assert_eq!(v, [1, 3]);
+        assert!(parse("x").is_err());
+        assert_eq!(parse("2").unwrap(), 4);
     }
 }
@@ -0,0 +1,14 @@
+use debug_log::{debug_dbg, debug_log, group};
+
+#[derive(Debug)]
+#[allow(dead_code)]
+struct MyStruct {
+    value: u8,
+}
+
+fn main() {
+    debug_log::set_debug("*");
+    group!("group");
+    debug_log!("haha");
+    debug_dbg!(MyStruct { value: 1 });
+}
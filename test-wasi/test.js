@@ -0,0 +1,8 @@
+const { readFileSync } = require("node:fs");
+const { WASI } = require("node:wasi");
+
+const wasi = new WASI({ version: "preview1" });
+const wasm = new WebAssembly.Module(
+  readFileSync(`${__dirname}/target/wasm32-wasip1/debug/test-wasi.wasm`),
+);
+wasi.start(new WebAssembly.Instance(wasm, wasi.getImportObject()));
@@ -25,4 +25,15 @@ impl MyStruct {
         debug_log::debug_log!("haha");
         debug_dbg!(self);
     }
+
+    #[allow(non_snake_case)]
+    pub fn logCollapsed(&self) {
+        debug_log::set_wasm_group_mode(debug_log::WasmGroupMode::Collapsed);
+        debug_log::group!("collapsed group");
+        {
+            debug_log::group!("nested group");
+            debug_dbg!(self);
+        }
+        debug_log::debug_log!("haha");
+    }
 }